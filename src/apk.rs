@@ -0,0 +1,565 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+use zip::ZipArchive;
+
+// chunk types, see frameworks/base/libs/androidfw/include/androidfw/ResourceTypes.h
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_TABLE_TYPE: u16 = 0x0002;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+const RES_TABLE_PACKAGE_TYPE: u16 = 0x0200;
+const RES_TABLE_TYPE_TYPE: u16 = 0x0201;
+
+// Res_value data types
+const TYPE_REFERENCE: u8 = 0x01;
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_HEX: u8 = 0x11;
+
+// android:* attribute resource ids
+const ATTR_VERSION_CODE: u32 = 0x0101021b;
+const ATTR_VERSION_NAME: u32 = 0x0101021c;
+const ATTR_LABEL: u32 = 0x01010001;
+const ATTR_MIN_SDK_VERSION: u32 = 0x0101020c;
+const ATTR_TARGET_SDK_VERSION: u32 = 0x01010270;
+
+// what Android assumes when <uses-sdk> or its minSdkVersion is missing
+const DEFAULT_MIN_SDK_VERSION: i32 = 1;
+
+// ABIs Neo Backup knows about, 64-bit first so they win when an app ships several
+const CPU_ARCHES: [&str; 4] = ["arm64-v8a", "x86_64", "armeabi-v7a", "x86"];
+
+const STRING_POOL_UTF8_FLAG: u32 = 1 << 8;
+const MAX_REFERENCE_DEPTH: usize = 8;
+
+#[derive(Clone, Default)]
+pub struct ApkManifest {
+    pub version_name: Option<String>,
+    pub version_code: Option<i32>,
+    pub min_sdk_version: Option<i32>,
+    pub target_sdk_version: Option<i32>,
+}
+
+// everything the migration reads from an app's APKs, so each one is opened and parsed only once
+#[derive(Clone)]
+pub struct ApkInfo {
+    pub manifest: ApkManifest,
+    pub label: Option<String>,
+    pub cpu_arch: String,
+}
+
+#[derive(Clone, Debug)]
+enum AttributeValue {
+    String(String),
+    Int(u32),
+    Reference(u32),
+    Other,
+}
+
+struct XmlAttribute {
+    name: String,
+    resource_id: Option<u32>,
+    value: AttributeValue,
+}
+
+struct XmlElement {
+    name: String,
+    attributes: Vec<XmlAttribute>,
+}
+
+impl XmlElement {
+    fn attribute(&self, resource_id: u32, name: &str) -> Option<&AttributeValue> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.resource_id == Some(resource_id))
+            .or_else(|| self.attributes.iter().find(|attr| attr.name == name))
+            .map(|attr| &attr.value)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+// walks the chunks in bytes[start..], yielding (type, header size, chunk bytes)
+fn chunks(bytes: &[u8], start: usize) -> impl Iterator<Item = (u16, usize, &[u8])> {
+    let mut offset = start;
+    std::iter::from_fn(move || {
+        let chunk_type = u16_at(bytes, offset)?;
+        let header_size = u16_at(bytes, offset + 2)? as usize;
+        let size = u32_at(bytes, offset + 4)? as usize;
+        if size < 8 {
+            return None;
+        }
+        let chunk = bytes.get(offset..offset + size)?;
+        offset += size;
+        Some((chunk_type, header_size, chunk))
+    })
+}
+
+fn parse_string_pool(chunk: &[u8]) -> Option<Vec<String>> {
+    let header_size = u16_at(chunk, 2)? as usize;
+    let string_count = u32_at(chunk, 8)? as usize;
+    let flags = u32_at(chunk, 16)?;
+    let strings_start = u32_at(chunk, 20)? as usize;
+    let is_utf8 = flags & STRING_POOL_UTF8_FLAG != 0;
+
+    (0..string_count)
+        .map(|i| {
+            let offset = strings_start + u32_at(chunk, header_size + i * 4)? as usize;
+            match is_utf8 {
+                true => read_utf8_string(chunk, offset),
+                false => read_utf16_string(chunk, offset),
+            }
+        })
+        .collect()
+}
+
+// UTF-8 pool strings are prefixed by their UTF-16 and UTF-8 lengths, each one or two bytes
+fn read_utf8_length(chunk: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let first = *chunk.get(offset)? as usize;
+    match first & 0x80 {
+        0 => Some((first, 1)),
+        _ => Some((((first & 0x7f) << 8) | *chunk.get(offset + 1)? as usize, 2)),
+    }
+}
+
+fn read_utf8_string(chunk: &[u8], offset: usize) -> Option<String> {
+    let (_, utf16_len_size) = read_utf8_length(chunk, offset)?;
+    let (len, utf8_len_size) = read_utf8_length(chunk, offset + utf16_len_size)?;
+    let start = offset + utf16_len_size + utf8_len_size;
+    Some(String::from_utf8_lossy(chunk.get(start..start + len)?).to_string())
+}
+
+fn read_utf16_string(chunk: &[u8], offset: usize) -> Option<String> {
+    let first = u16_at(chunk, offset)? as usize;
+    let (len, offset) = match first & 0x8000 {
+        0 => (first, offset + 2),
+        _ => (
+            ((first & 0x7fff) << 16) | u16_at(chunk, offset + 2)? as usize,
+            offset + 4,
+        ),
+    };
+    let units: Vec<u16> = (0..len)
+        .map(|i| u16_at(chunk, offset + i * 2))
+        .collect::<Option<_>>()?;
+    Some(String::from_utf16_lossy(&units))
+}
+
+fn attribute_value(data_type: u8, data: u32, strings: &[String]) -> AttributeValue {
+    match data_type {
+        TYPE_STRING => strings
+            .get(data as usize)
+            .map(|s| AttributeValue::String(s.clone()))
+            .unwrap_or(AttributeValue::Other),
+        TYPE_INT_DEC | TYPE_INT_HEX => AttributeValue::Int(data),
+        TYPE_REFERENCE => AttributeValue::Reference(data),
+        _ => AttributeValue::Other,
+    }
+}
+
+// decodes the elements of a compiled (binary) XML file such as AndroidManifest.xml
+fn parse_binary_xml(bytes: &[u8]) -> Result<Vec<XmlElement>, io::Error> {
+    if u16_at(bytes, 0) != Some(RES_XML_TYPE) {
+        return Err(invalid_data("not a binary XML file"));
+    }
+    let header_size = u16_at(bytes, 2).unwrap_or(8) as usize;
+
+    let mut strings = Vec::new();
+    let mut resource_ids = Vec::new();
+    let mut elements = Vec::new();
+    for (chunk_type, chunk_header_size, chunk) in chunks(bytes, header_size) {
+        match chunk_type {
+            RES_STRING_POOL_TYPE => {
+                strings =
+                    parse_string_pool(chunk).ok_or_else(|| invalid_data("bad string pool"))?;
+            }
+            RES_XML_RESOURCE_MAP_TYPE => {
+                resource_ids = (chunk_header_size..chunk.len())
+                    .step_by(4)
+                    .filter_map(|offset| u32_at(chunk, offset))
+                    .collect();
+            }
+            RES_XML_START_ELEMENT_TYPE => {
+                let ext = chunk_header_size;
+                let name = u32_at(chunk, ext + 4)
+                    .and_then(|i| strings.get(i as usize))
+                    .cloned()
+                    .unwrap_or_default();
+                let attribute_start = u16_at(chunk, ext + 8).unwrap_or(20) as usize;
+                let attribute_size = u16_at(chunk, ext + 10).unwrap_or(20) as usize;
+                let attribute_count = u16_at(chunk, ext + 12).unwrap_or(0) as usize;
+
+                let attributes = (0..attribute_count)
+                    .filter_map(|i| {
+                        let offset = ext + attribute_start + i * attribute_size;
+                        let name_index = u32_at(chunk, offset + 4)?;
+                        let raw_value = u32_at(chunk, offset + 8)?;
+                        let data_type = *chunk.get(offset + 15)?;
+                        let data = u32_at(chunk, offset + 16)?;
+
+                        // prefer the raw string, the typed value may be a string pool index too
+                        let value = match raw_value {
+                            u32::MAX => attribute_value(data_type, data, &strings),
+                            _ => strings
+                                .get(raw_value as usize)
+                                .map(|s| AttributeValue::String(s.clone()))
+                                .unwrap_or(AttributeValue::Other),
+                        };
+                        Some(XmlAttribute {
+                            name: strings
+                                .get(name_index as usize)
+                                .cloned()
+                                .unwrap_or_default(),
+                            resource_id: resource_ids.get(name_index as usize).copied(),
+                            value,
+                        })
+                    })
+                    .collect();
+
+                elements.push(XmlElement { name, attributes });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(elements)
+}
+
+// one value of a resource entry, along with the language/country of the configuration it is for
+struct ResourceValue {
+    language: [u8; 2],
+    country: [u8; 2],
+    data_type: u8,
+    data: u32,
+}
+
+// the parts of resources.arsc needed to resolve string and integer references
+struct ResourceTable {
+    strings: Vec<String>,
+    values: HashMap<u32, Vec<ResourceValue>>,
+}
+
+impl ResourceTable {
+    fn parse(bytes: &[u8]) -> Result<Self, io::Error> {
+        if u16_at(bytes, 0) != Some(RES_TABLE_TYPE) {
+            return Err(invalid_data("not a resource table"));
+        }
+        let header_size = u16_at(bytes, 2).unwrap_or(12) as usize;
+
+        let mut table = ResourceTable {
+            strings: Vec::new(),
+            values: HashMap::new(),
+        };
+        for (chunk_type, package_header_size, chunk) in chunks(bytes, header_size) {
+            match chunk_type {
+                RES_STRING_POOL_TYPE => {
+                    table.strings =
+                        parse_string_pool(chunk).ok_or_else(|| invalid_data("bad string pool"))?;
+                }
+                RES_TABLE_PACKAGE_TYPE => {
+                    let package_id = u32_at(chunk, 8).unwrap_or(0) & 0xff;
+                    for (chunk_type, type_header_size, type_chunk) in
+                        chunks(chunk, package_header_size)
+                    {
+                        if chunk_type == RES_TABLE_TYPE_TYPE {
+                            table.add_type_chunk(package_id, type_header_size, type_chunk);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(table)
+    }
+
+    fn add_type_chunk(&mut self, package_id: u32, header_size: usize, chunk: &[u8]) {
+        const FLAG_SPARSE: u8 = 0x01;
+        const FLAG_OFFSET16: u8 = 0x02;
+        const ENTRY_FLAG_COMPLEX: u16 = 0x0001;
+        const ENTRY_FLAG_COMPACT: u16 = 0x0008;
+
+        let (Some(&type_id), Some(&flags), Some(entry_count), Some(entries_start)) = (
+            chunk.get(8),
+            chunk.get(9),
+            u32_at(chunk, 12),
+            u32_at(chunk, 16),
+        ) else {
+            return;
+        };
+        // ResTable_config starts at 20: size, mcc, mnc, language[2], country[2]
+        let language = [
+            chunk.get(28).copied().unwrap_or(0),
+            chunk.get(29).copied().unwrap_or(0),
+        ];
+        let country = [
+            chunk.get(30).copied().unwrap_or(0),
+            chunk.get(31).copied().unwrap_or(0),
+        ];
+
+        let entries: Vec<(u32, usize)> = match (flags & FLAG_SPARSE, flags & FLAG_OFFSET16) {
+            (0, 0) => (0..entry_count)
+                .filter_map(|i| {
+                    let offset = u32_at(chunk, header_size + i as usize * 4)?;
+                    (offset != u32::MAX).then_some((i, offset as usize))
+                })
+                .collect(),
+            (0, _) => (0..entry_count)
+                .filter_map(|i| {
+                    let offset = u16_at(chunk, header_size + i as usize * 2)?;
+                    (offset != u16::MAX).then_some((i, offset as usize * 4))
+                })
+                .collect(),
+            _ => (0..entry_count as usize)
+                .filter_map(|i| {
+                    let index = u16_at(chunk, header_size + i * 4)?;
+                    let offset = u16_at(chunk, header_size + i * 4 + 2)?;
+                    Some((index as u32, offset as usize * 4))
+                })
+                .collect(),
+        };
+
+        for (index, offset) in entries {
+            let entry = entries_start as usize + offset;
+            let Some(entry_flags) = u16_at(chunk, entry + 2) else {
+                continue;
+            };
+            let (data_type, data) = if entry_flags & ENTRY_FLAG_COMPACT != 0 {
+                ((entry_flags >> 8) as u8, u32_at(chunk, entry + 4))
+            } else if entry_flags & ENTRY_FLAG_COMPLEX != 0 {
+                continue;
+            } else {
+                let entry_size = u16_at(chunk, entry).unwrap_or(8) as usize;
+                let value = entry + entry_size;
+                (
+                    chunk.get(value + 3).copied().unwrap_or(0),
+                    u32_at(chunk, value + 4),
+                )
+            };
+            let Some(data) = data else {
+                continue;
+            };
+
+            let resource_id = (package_id << 24) | ((type_id as u32) << 16) | index;
+            self.values
+                .entry(resource_id)
+                .or_default()
+                .push(ResourceValue {
+                    language,
+                    country,
+                    data_type,
+                    data,
+                });
+        }
+    }
+
+    // picks the default configuration, then English, then whatever comes first
+    fn preferred_value(&self, resource_id: u32) -> Option<&ResourceValue> {
+        let values = self.values.get(&resource_id)?;
+        values
+            .iter()
+            .find(|v| v.language == [0, 0] && v.country == [0, 0])
+            .or_else(|| values.iter().find(|v| &v.language == b"en"))
+            .or_else(|| values.first())
+    }
+
+    fn resolve(&self, resource_id: u32) -> Option<AttributeValue> {
+        let mut resource_id = resource_id;
+        for _ in 0..MAX_REFERENCE_DEPTH {
+            let value = self.preferred_value(resource_id)?;
+            match attribute_value(value.data_type, value.data, &self.strings) {
+                AttributeValue::Reference(next) => resource_id = next,
+                resolved => return Some(resolved),
+            }
+        }
+        None
+    }
+}
+
+fn read_zip_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>, io::Error> {
+    let mut entry = archive.by_name(name).map_err(io::Error::other)?;
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// resolves a resource reference through resources.arsc, other values are returned as they are
+fn resolved_value(
+    value: Option<&AttributeValue>,
+    resources: Option<&ResourceTable>,
+) -> Option<AttributeValue> {
+    match value? {
+        AttributeValue::Reference(resource_id) => resources?.resolve(*resource_id),
+        value => Some(value.clone()),
+    }
+}
+
+fn string_value(
+    value: Option<&AttributeValue>,
+    resources: Option<&ResourceTable>,
+) -> Option<String> {
+    match resolved_value(value, resources)? {
+        AttributeValue::String(s) => Some(s),
+        AttributeValue::Int(i) => Some(i.to_string()),
+        _ => None,
+    }
+}
+
+fn int_value(value: Option<&AttributeValue>, resources: Option<&ResourceTable>) -> Option<u32> {
+    match resolved_value(value, resources)? {
+        AttributeValue::Int(i) => Some(i),
+        AttributeValue::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn read_manifest_elements(archive: &mut ZipArchive<File>) -> Result<Vec<XmlElement>, io::Error> {
+    parse_binary_xml(&read_zip_entry(archive, "AndroidManifest.xml")?)
+}
+
+// resources.arsc is only parsed when an attribute actually references a resource
+fn read_resources_if_referenced(
+    archive: &mut ZipArchive<File>,
+    values: &[Option<&AttributeValue>],
+) -> Option<ResourceTable> {
+    match values
+        .iter()
+        .any(|value| matches!(value, Some(AttributeValue::Reference(_))))
+    {
+        true => read_zip_entry(archive, "resources.arsc")
+            .and_then(|bytes| ResourceTable::parse(&bytes))
+            .ok(),
+        false => None,
+    }
+}
+
+fn open_apk(apk_path: &Path) -> Result<ZipArchive<File>, io::Error> {
+    ZipArchive::new(File::open(apk_path)?).map_err(io::Error::other)
+}
+
+// versionName/versionCode and the SDK versions from the APK's binary AndroidManifest.xml, and
+// the <application> android:label when asked for, resolved for the default (or English)
+// configuration
+fn read_manifest_and_label(
+    archive: &mut ZipArchive<File>,
+    with_label: bool,
+) -> Result<(ApkManifest, Option<String>), io::Error> {
+    let elements = read_manifest_elements(archive)?;
+
+    let manifest = elements
+        .iter()
+        .find(|element| element.name == "manifest")
+        .ok_or_else(|| invalid_data("no <manifest> element"))?;
+    let version_name = manifest.attribute(ATTR_VERSION_NAME, "versionName");
+    let version_code = manifest.attribute(ATTR_VERSION_CODE, "versionCode");
+    let uses_sdk = elements.iter().find(|element| element.name == "uses-sdk");
+    let min_sdk_version =
+        uses_sdk.and_then(|element| element.attribute(ATTR_MIN_SDK_VERSION, "minSdkVersion"));
+    let target_sdk_version =
+        uses_sdk.and_then(|element| element.attribute(ATTR_TARGET_SDK_VERSION, "targetSdkVersion"));
+    let label = match with_label {
+        true => elements
+            .iter()
+            .find(|element| element.name == "application")
+            .and_then(|element| element.attribute(ATTR_LABEL, "label")),
+        false => None,
+    };
+    // parsed once for all of them
+    let resources = read_resources_if_referenced(
+        archive,
+        &[
+            version_name,
+            version_code,
+            min_sdk_version,
+            target_sdk_version,
+            label,
+        ],
+    );
+
+    // a preview SDK's codename, e.g. "Baklava", isn't a number and is left unknown
+    let min_sdk_version = match min_sdk_version {
+        Some(_) => int_value(min_sdk_version, resources.as_ref()).map(|version| version as i32),
+        None => Some(DEFAULT_MIN_SDK_VERSION),
+    };
+    // the target defaults to the minimum
+    let target_sdk_version = match target_sdk_version {
+        Some(_) => int_value(target_sdk_version, resources.as_ref()).map(|version| version as i32),
+        None => min_sdk_version,
+    };
+
+    let manifest = ApkManifest {
+        version_name: string_value(version_name, resources.as_ref()),
+        version_code: int_value(version_code, resources.as_ref())
+            .map(|version_code| version_code as i32),
+        min_sdk_version,
+        target_sdk_version,
+    };
+    let label = string_value(label, resources.as_ref())
+        .map(|label| label.trim().to_string())
+        .filter(|label| !label.is_empty());
+    Ok((manifest, label))
+}
+
+pub fn read_apk_manifest(apk_path: &Path) -> Result<ApkManifest, io::Error> {
+    read_manifest_and_label(&mut open_apk(apk_path)?, false).map(|(manifest, _)| manifest)
+}
+
+// native libs live in lib/<abi>/ of the base or split APKs
+fn native_abis(archive: &ZipArchive<File>) -> Vec<String> {
+    archive
+        .file_names()
+        .filter_map(|name| name.strip_prefix("lib/")?.split_once('/'))
+        .map(|(abi, _)| abi.to_string())
+        .collect()
+}
+
+// the manifest and label of an app's base.apk, and the ABI of all of its APKs; apps without
+// native libs run on the device's default ABI
+pub fn read_apk_info(apk_paths: &[PathBuf], default_arch: &str) -> Result<ApkInfo, io::Error> {
+    let base_apk_path = apk_paths
+        .iter()
+        .find(|apk_path| apk_path.file_name().is_some_and(|name| name == "base.apk"))
+        .ok_or_else(|| invalid_data("no base.apk"))?;
+    let mut base_archive = open_apk(base_apk_path)?;
+    let (manifest, label) = read_manifest_and_label(&mut base_archive, true)?;
+
+    let abis: Vec<String> = native_abis(&base_archive)
+        .into_iter()
+        .chain(
+            apk_paths
+                .iter()
+                .filter(|apk_path| *apk_path != base_apk_path)
+                .filter_map(|apk_path| open_apk(apk_path).ok())
+                .flat_map(|archive| native_abis(&archive)),
+        )
+        .collect();
+    let cpu_arch = CPU_ARCHES
+        .iter()
+        .find(|arch| abis.iter().any(|abi| abi == *arch))
+        .map(|arch| arch.to_string())
+        .unwrap_or_else(|| default_arch.to_string());
+
+    Ok(ApkInfo {
+        manifest,
+        label,
+        cpu_arch,
+    })
+}
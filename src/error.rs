@@ -0,0 +1,36 @@
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EvacuateError {
+    #[error("Couldn't decompress {}: {source}", path.display())]
+    Decompress { path: PathBuf, source: io::Error },
+
+    #[error("Chunk {} failed checksum ({digest_kind} digest doesn't match), copy it off the device again or use --no-verify to try anyway", path.display())]
+    Checksum {
+        path: PathBuf,
+        digest_kind: &'static str,
+    },
+
+    #[error("Couldn't read the tar {}: {source}", path.display())]
+    TarRead { path: PathBuf, source: io::Error },
+
+    #[error("Couldn't read the manifest of {}: {source}", path.display())]
+    ApkParse { path: PathBuf, source: io::Error },
+
+    #[error("Couldn't migrate {package_name} of user {user_id}: {source}")]
+    Package {
+        user_id: i32,
+        package_name: String,
+        source: io::Error,
+    },
+
+    #[error("Interrupted, the unfinished backups were removed")]
+    Interrupted,
+
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    Other(#[from] io::Error),
+}
@@ -0,0 +1,4745 @@
+pub mod apk;
+mod error;
+
+use apk::{read_apk_info, read_apk_manifest, ApkInfo};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+pub use error::EvacuateError;
+use flate2::{
+    bufread::{DeflateDecoder, MultiGzDecoder},
+    write::GzEncoder,
+    CrcReader,
+};
+use glob::Pattern;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use md5::Md5;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write},
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{self, Duration, Instant, SystemTime},
+};
+use sysinfo::Disks;
+use tar::{Archive, EntryType, Header};
+use walkdir::WalkDir;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+pub const DEFAULT_OUTPUT_DIR: &str = "twrp_evacuate_migrated";
+pub const DECOMPRESSED_TAR_DIR: &str = "decompressed_temp";
+pub const APK_TEMP_DIR: &str = "apk_temp";
+pub const APKS_DIR: &str = "apks";
+pub const DEFAULT_READ_BUFFER_KIB: usize = 64;
+// unlike credential encrypted data (/data/data for user 0), there is no separate location for
+// user 0: its device protected data is in /data/user_de/0 like everyone else's
+const DEFAULT_DE_PATH_TEMPLATES: [&str; 1] = ["/data/user_de/{user}"];
+const EXCLUDED_APP_DATA_DIRS: [&str; 3] = ["cache", "code_cache", "no_backup"];
+pub const DEFAULT_MAX_OPEN_ARCHIVES: usize = 64;
+pub const DEFAULT_CPU_ARCH: &str = "arm64-v8a";
+pub const DEFAULT_DEVICE_BACKUP_DIR: &str = "/storage/emulated/0/NeoBackup";
+// Neo Backup releases and the backupVersionCode (major * 1000 + minor) their backups carry
+pub const NEO_BACKUP_VERSIONS: [(&str, i32); 4] =
+    [("8.0", 8000), ("8.1", 8001), ("8.2", 8002), ("8.3", 8003)];
+pub const DEFAULT_NEO_BACKUP_VERSION: &str = "8.3";
+
+// how the data archives are compressed; Neo Backup reads both
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // file extension, which is also the compressionType Neo Backup expects in .properties
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    // levels the codec accepts; gzip's 0 stores the data uncompressed
+    pub fn level_range(self) -> RangeInclusive<u32> {
+        match self {
+            Compression::Gzip => 0..=9,
+            Compression::Zstd => 1..=22,
+        }
+    }
+}
+
+// timezone the backup dates are written in; dates without one (TWRP folder names, --backup-date)
+// are read in it too
+#[derive(Clone, Copy)]
+pub enum Timezone {
+    Local,
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    // "local", an IANA name like "Europe/Berlin" or an offset like "+02:00"
+    fn from_str(timezone: &str) -> Result<Self, Self::Err> {
+        if timezone.eq_ignore_ascii_case("local") {
+            return Ok(Timezone::Local);
+        }
+        timezone
+            .parse::<Tz>()
+            .map(Timezone::Named)
+            .or_else(|_| timezone.parse::<FixedOffset>().map(Timezone::Fixed))
+            .map_err(|_| format!("unknown timezone {:?}", timezone))
+    }
+}
+
+impl Timezone {
+    // the wall clock time in this timezone, and back
+    fn to_naive(self, time: SystemTime) -> NaiveDateTime {
+        let time: DateTime<Utc> = time.into();
+        match self {
+            Timezone::Local => time.with_timezone(&Local).naive_local(),
+            Timezone::Named(tz) => time.with_timezone(&tz).naive_local(),
+            Timezone::Fixed(offset) => time.with_timezone(&offset).naive_local(),
+        }
+    }
+
+    fn to_system_time(self, datetime: &NaiveDateTime) -> Option<SystemTime> {
+        match self {
+            Timezone::Local => Local
+                .from_local_datetime(datetime)
+                .earliest()
+                .map(Into::into),
+            Timezone::Named(tz) => tz.from_local_datetime(datetime).earliest().map(Into::into),
+            Timezone::Fixed(offset) => offset
+                .from_local_datetime(datetime)
+                .earliest()
+                .map(Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// One JSON document grouping the packages by user
+    Json,
+    /// One JSON object per line, for each package and warning
+    Ndjson,
+}
+
+struct Config {
+    output_dir: String,
+    read_buffer_size: usize,
+    exclude_patterns: Vec<Pattern>,
+    include_cache: bool,
+    selinux: bool,
+    normalize_owner: bool,
+    compression: Compression,
+    compression_level: Option<u32>,
+    packages: HashSet<PackageName>,
+    excluded_packages: Vec<Pattern>,
+    skip_system: bool,
+    data_only: bool,
+    apk_backups: bool,
+    // packages with an APK in /data/app, filled in once the backup is indexed
+    installed_packages: HashSet<PackageName>,
+    compact_json: bool,
+    verify_output: bool,
+    verify_apk: bool,
+    verify_checksums: bool,
+    shared_storage: bool,
+    archive_slots: Slots,
+    tar_reader_slots: Slots,
+    apk_csv: Option<PathBuf>,
+    de_path_templates: Vec<String>,
+    default_cpu_arch: String,
+    backup_version_code: i32,
+    timezone: Timezone,
+}
+
+// caps how many of the parallel workers do something at once: keep an output archive open, so
+// users with hundreds of packages don't run out of file descriptors or memory, or read the
+// decompressed tars, so a spinning disk isn't seeking back and forth between them
+struct Slots {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+struct Slot<'a> {
+    slots: &'a Slots,
+}
+
+impl Slots {
+    fn new(limit: usize) -> Self {
+        Slots {
+            available: Mutex::new(limit.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    // blocks until a slot is free, it's given back when the guard is dropped
+    fn acquire(&self) -> Slot<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Slot { slots: self }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.slots.available.lock().unwrap() += 1;
+        self.slots.released.notify_one();
+    }
+}
+
+// Android's shared storage (e.g. when running in Termux on the device) is FUSE/SAF backed and
+// refuses changing file times or ownership
+fn is_android_shared_storage(path: &Path) -> bool {
+    ["/storage/", "/sdcard", "/mnt/sdcard", "/mnt/user/"]
+        .iter()
+        .any(|prefix| path.to_string_lossy().starts_with(prefix))
+}
+
+// without --package every package is migrated; --exclude wins when both match
+fn is_selected_package(package_name: &str, config: &Config) -> bool {
+    let excluded = config
+        .excluded_packages
+        .iter()
+        .any(|pattern| pattern.matches(package_name));
+    !excluded
+        && (config.packages.is_empty() || config.packages.contains(package_name))
+        && !is_skipped_system_app(package_name, config)
+}
+
+// system apps live on the system partition, so the data backup only has their data; they
+// rarely restore on another device
+fn is_skipped_system_app(package_name: &str, config: &Config) -> bool {
+    config.skip_system && !config.installed_packages.contains(package_name)
+}
+
+// example of an ApkFsItem: "/data/app/~~YUW09CEoPo_qnb20Rnmw2Q==/com.machiav3lli.backup-DqFd2HhZgfqT9Ep65qCtZQ=="
+// root_dir_name: "~~YUW09CEoPo_qnb20Rnmw2Q=="
+// instance_dir_name: "com.machiav3lli.backup-DqFd2HhZgfqT9Ep65qCtZQ=="
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApkFsItem {
+    pub root_dir_name: Option<String>,
+    pub instance_dir_name: String,
+}
+
+// an install dir is named "<package>-<suffix>", or "<package>-1" on older Android versions.
+// Package names can't hold a dash while the URL-safe base64 suffix can, so the package name ends
+// at the first one; without a dash the whole name is taken
+fn instance_dir_package_name(instance_dir_name: &str) -> &str {
+    instance_dir_name
+        .split_once('-')
+        .map_or(instance_dir_name, |(package_name, _)| package_name)
+}
+
+impl ApkFsItem {
+    pub fn package_name(&self) -> &str {
+        instance_dir_package_name(&self.instance_dir_name)
+    }
+
+    pub fn dir_path(&self) -> String {
+        match &self.root_dir_name {
+            Some(root_dir_name) => {
+                format!("/data/app/{}/{}", root_dir_name, self.instance_dir_name)
+            }
+            None => format!("/data/app/{}", self.instance_dir_name),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NeoBackupProperties {
+    pub backup_version_code: i32,
+    pub package_name: String,
+    pub package_label: String,
+    pub version_name: String,
+    pub version_code: i32,
+    pub backup_date: String,
+    // no APK in /data/app, so it came with the ROM; missing from older .properties files
+    #[serde(default)]
+    pub is_system: bool,
+    pub has_apk: bool,
+    pub has_app_data: bool,
+    pub has_devices_protected_data: bool,
+    // missing from .properties files written before these kinds were migrated
+    #[serde(default)]
+    pub has_obb_data: bool,
+    #[serde(default)]
+    pub has_external_data: bool,
+    #[serde(default)]
+    pub has_media_data: bool,
+    pub compression_type: String,
+    pub cpu_arch: String,
+    pub size: i64,
+}
+
+// a migrated backup as listed in the report: its .properties content and where it was written
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageReport {
+    pub backup_dir: String,
+    #[serde(flatten)]
+    pub properties: NeoBackupProperties,
+    // read from the APK's manifest, Neo Backup's .properties have no place for them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_sdk_version: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_sdk_version: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserReport {
+    pub user_id: UserId,
+    pub packages: Vec<PackageReport>,
+}
+
+#[derive(Serialize)]
+pub struct MigrationReport {
+    pub users: Vec<UserReport>,
+    pub warnings: Vec<String>,
+    // temporary directories removed at the end of the run, for the closing message
+    #[serde(skip)]
+    pub removed_temp_dirs: Vec<String>,
+}
+
+// a line of the ndjson report, tagged so consumers can tell packages and warnings apart
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ReportLine<'a> {
+    Package {
+        #[serde(rename = "userId")]
+        user_id: UserId,
+        #[serde(flatten)]
+        package: &'a PackageReport,
+    },
+    Warning {
+        message: &'a str,
+    },
+}
+
+// resolves the display label Neo Backup shows for a package
+trait LabelResolver: Send + Sync {
+    fn resolve(&self, package_name: &str) -> Option<String>;
+}
+
+// labels from a "package,label" CSV file maintained by the user
+struct CsvLabelResolver {
+    labels: HashMap<PackageName, String>,
+}
+
+impl CsvLabelResolver {
+    fn load(csv_path: &Path) -> Result<Self, io::Error> {
+        let labels = fs::read_to_string(csv_path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (package_name, label) = line.split_once(',')?;
+                let label = label.trim().trim_matches('"').replace("\"\"", "\"");
+                Some((package_name.trim().trim_matches('"').to_string(), label))
+            })
+            .filter(|(package_name, label)| is_safe_package_name(package_name) && !label.is_empty())
+            .collect();
+
+        Ok(CsvLabelResolver { labels })
+    }
+}
+
+impl LabelResolver for CsvLabelResolver {
+    fn resolve(&self, package_name: &str) -> Option<String> {
+        self.labels.get(package_name).cloned()
+    }
+}
+
+// labels from the <application> element of the package's migrated base.apk
+struct ApkLabelResolver {
+    apk_infos: Arc<ApkInfoCache>,
+}
+
+impl LabelResolver for ApkLabelResolver {
+    fn resolve(&self, package_name: &str) -> Option<String> {
+        self.apk_infos.get(package_name)?.label
+    }
+}
+
+// what was read from each package's migrated APKs; the same APKs serve the properties of every
+// user, and the label of those without an APK of their own
+struct ApkInfoCache {
+    output_dir: String,
+    default_cpu_arch: String,
+    infos: Mutex<HashMap<PackageName, Option<ApkInfo>>>,
+}
+
+impl ApkInfoCache {
+    // the APKs in apk_dir, unless the package's were read already
+    fn get_or_read(&self, package_name: &str, apk_dir: &Path) -> Option<ApkInfo> {
+        if let Some(info) = self.infos.lock().unwrap().get(package_name) {
+            return info.clone();
+        }
+
+        let apk_paths: Vec<PathBuf> = fs::read_dir(apk_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("apk"))
+            .collect();
+        let info = match read_apk_info(&apk_paths, &self.default_cpu_arch) {
+            Ok(info) => Some(info),
+            Err(source) => {
+                let path = apk_dir.join("base.apk");
+                record_warning(EvacuateError::ApkParse { path, source }.to_string());
+                None
+            }
+        };
+        self.infos
+            .lock()
+            .unwrap()
+            .insert(package_name.to_string(), info.clone());
+        info
+    }
+
+    // wherever the package's base.apk was migrated to
+    fn get(&self, package_name: &str) -> Option<ApkInfo> {
+        if let Some(info) = self.infos.lock().unwrap().get(package_name) {
+            return info.clone();
+        }
+        let base_apk_path = find_migrated_base_apk(&self.output_dir, package_name)?;
+        self.get_or_read(package_name, base_apk_path.parent()?)
+    }
+}
+
+// base.apk sits in the app dir of the user it was moved to, or in a dated folder once assembled
+fn find_migrated_base_apk(output_dir: &str, package_name: &str) -> Option<PathBuf> {
+    fs::read_dir(output_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path().join(package_name))
+        .filter(|app_dir| app_dir.is_dir())
+        .find_map(|app_dir| {
+            let apk_path = app_dir.join("base.apk");
+            if apk_path.exists() {
+                return Some(apk_path);
+            }
+            fs::read_dir(&app_dir)
+                .ok()?
+                .filter_map(Result::ok)
+                .map(|entry| entry.path().join("base.apk"))
+                .find(|apk_path| apk_path.exists())
+        })
+}
+
+// asks each resolver in priority order and takes the first label found
+#[derive(Default)]
+struct LabelResolverChain {
+    resolvers: Vec<Box<dyn LabelResolver>>,
+}
+
+impl LabelResolverChain {
+    fn push(&mut self, resolver: Box<dyn LabelResolver>) {
+        self.resolvers.push(resolver);
+    }
+}
+
+impl LabelResolver for LabelResolverChain {
+    fn resolve(&self, package_name: &str) -> Option<String> {
+        self.resolvers
+            .iter()
+            .find_map(|resolver| resolver.resolve(package_name))
+    }
+}
+
+struct NeoBackupPropertiesFile {
+    name: String,
+    content: NeoBackupProperties,
+    min_sdk_version: Option<i32>,
+    target_sdk_version: Option<i32>,
+}
+
+pub type PackageName = String;
+
+// problems worth a look that don't stop the migration, reported at the end of the run
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// the warnings collected so far, listed at the end of a run
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().clone()
+}
+
+fn record_warning(message: String) {
+    warn!("{}", message);
+    WARNINGS.lock().unwrap().push(message);
+}
+
+// one per CPU core, rayon's default as well
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+// set by interrupt(), running work stops at the next package or read and fails
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// asks a running migration to stop, e.g. from a Ctrl-C handler; Migrator::discard_unfinished()
+// then removes what it left behind
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+fn check_interrupted() -> Result<(), io::Error> {
+    match is_interrupted() {
+        // not ErrorKind::Interrupted, io::copy and friends retry on that
+        true => Err(io::Error::other("interrupted")),
+        false => Ok(()),
+    }
+}
+
+// stops long reads of backup chunks and tars when the run is interrupted
+struct InterruptibleRead<R: Read>(R);
+
+impl<R: Read> Read for InterruptibleRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_interrupted()?;
+        self.0.read(buf)
+    }
+}
+
+pub type UserId = i32;
+
+// rough overview of what a backup contains, gathered before the heavy extraction starts
+#[derive(Default)]
+struct BackupScope {
+    packages: HashMap<UserId, HashSet<PackageName>>,
+    apk_packages: HashSet<PackageName>,
+    skipped_system_packages: HashSet<PackageName>,
+    size: u64,
+}
+
+// package names end up as directory names under the output dir, so anything that could escape it
+// (".", "..", path separators) or that isn't a valid Android package name is rejected
+fn is_safe_package_name(package_name: &str) -> bool {
+    !package_name.is_empty()
+        && !package_name.chars().all(|c| c == '.')
+        && package_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// one tar entry, enough to list it and to copy its data straight out of the tar later
+struct TarIndexEntry {
+    path: String,
+    data_offset: u64,
+    size: u64,
+    entry_type: EntryType,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    username: Option<String>,
+    groupname: Option<String>,
+    mtime: u64,
+    // target of symlinks and hard links
+    link_name: Option<String>,
+    // extended attributes from the entry's PAX header, keyed as they were recorded
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+// all entries of a decompressed tar, read once and sorted by path so every user's and package's
+// entries sit next to each other
+struct TarIndex {
+    tar_path: PathBuf,
+    entries: Vec<TarIndexEntry>,
+}
+
+impl TarIndex {
+    // the entry of dir_path itself (if any) and everything below it, in tar order
+    fn entries_under(&self, dir_path: &str) -> Vec<&TarIndexEntry> {
+        let dir_path = dir_path.trim_end_matches('/');
+        let prefix = format!("{}/", dir_path);
+        let start = self
+            .entries
+            .partition_point(|entry| entry.path.as_str() < dir_path);
+        let children_start = self
+            .entries
+            .partition_point(|entry| entry.path.as_str() < prefix.as_str());
+        let children_len =
+            self.entries[children_start..].partition_point(|entry| entry.path.starts_with(&prefix));
+
+        let mut entries: Vec<&TarIndexEntry> = self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.path == dir_path)
+            .chain(&self.entries[children_start..children_start + children_len])
+            .collect();
+        entries.sort_by_key(|entry| entry.data_offset);
+        entries
+    }
+
+    fn find(&self, path: &str) -> Option<&TarIndexEntry> {
+        self.entries
+            .binary_search_by(|entry| entry.path.as_str().cmp(path))
+            .ok()
+            .map(|position| &self.entries[position])
+    }
+}
+
+// "SCHILY.xattr.<name>" is the usual PAX form of an extended attribute (security.capability of
+// native executables, user.* of the app, ...), TWRP records SELinux labels as
+// "RHT.security.selinux"
+fn is_xattr_key(key: &str) -> bool {
+    key.starts_with("SCHILY.xattr.") || key == "RHT.security.selinux"
+}
+
+fn is_selinux_xattr_key(key: &str) -> bool {
+    key == "SCHILY.xattr.security.selinux" || key == "RHT.security.selinux"
+}
+
+fn read_xattrs(entry: &mut tar::Entry<impl Read>) -> Vec<(String, Vec<u8>)> {
+    let Ok(Some(extensions)) = entry.pax_extensions() else {
+        return Vec::new();
+    };
+    extensions
+        .filter_map(Result::ok)
+        .filter_map(|extension| {
+            let key = extension.key().ok()?;
+            is_xattr_key(key).then(|| (key.to_string(), extension.value_bytes().to_vec()))
+        })
+        .collect()
+}
+
+// largest size the 11 octal digits of a tar header hold (8 GiB); the GNU header stores bigger
+// ones in binary, and they also get a PAX record for readers that don't understand that
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+// writes the extended attributes and an oversized entry's size as a PAX header, which applies to
+// the entry appended next; SELinux labels are left out with --no-selinux
+fn append_pax_header<W: Write>(
+    xattrs: &[(String, Vec<u8>)],
+    size: u64,
+    dest_tar: &mut tar::Builder<W>,
+    config: &Config,
+) -> Result<(), io::Error> {
+    let size_value = size.to_string();
+    let size_record = (size > MAX_OCTAL_SIZE).then_some(("size", size_value.as_bytes()));
+    dest_tar.append_pax_extensions(
+        xattrs
+            .iter()
+            .filter(|(key, _)| config.selinux || !is_selinux_xattr_key(key))
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+            .chain(size_record),
+    )
+}
+
+// walks the tar once; everything later works off the index and seeks to the data it needs
+fn build_tar_index(
+    tar_path: &Path,
+    bar_scan: &ProgressBar,
+    config: &Config,
+) -> Result<TarIndex, EvacuateError> {
+    let tar_error = |source| EvacuateError::TarRead {
+        path: tar_path.to_path_buf(),
+        source,
+    };
+    // this reads the whole tar, so report progress by bytes read
+    let file = File::open(tar_path).map_err(tar_error)?;
+    bar_scan.inc_length(file.metadata().map_err(tar_error)?.len());
+    let reader = BufReader::with_capacity(config.read_buffer_size, file);
+    index_tar_stream(InterruptibleRead(bar_scan.wrap_read(reader)), tar_path).map_err(tar_error)
+}
+
+// indexes a backup chunk while decompressing it on the fly, nothing is written to disk; the
+// data offsets point into the decompressed stream, so this index is only good for listing
+fn build_win_file_index(
+    win_path: &Path,
+    bar_scan: &ProgressBar,
+    config: &Config,
+) -> Result<TarIndex, EvacuateError> {
+    let decompress_error = |source| EvacuateError::Decompress {
+        path: win_path.to_path_buf(),
+        source,
+    };
+    let file = File::open(win_path).map_err(decompress_error)?;
+    bar_scan.inc_length(file.metadata().map_err(decompress_error)?.len());
+    let reader = BufReader::with_capacity(
+        config.read_buffer_size,
+        InterruptibleRead(bar_scan.wrap_read(file)),
+    );
+    let reader = win_file_reader(reader, win_path, config).map_err(decompress_error)?;
+    index_tar_stream(reader, win_path).map_err(decompress_error)
+}
+
+fn index_tar_stream(reader: impl Read, tar_path: &Path) -> Result<TarIndex, io::Error> {
+    let mut archive = Archive::new(reader);
+
+    let mut entries = Vec::new();
+    for mut entry in archive.entries()?.filter_map(Result::ok) {
+        let xattrs = read_xattrs(&mut entry);
+        let Some(path) = entry
+            .path()
+            .ok()
+            .and_then(|path| path.to_str().map(String::from))
+        else {
+            continue;
+        };
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        entries.push(TarIndexEntry {
+            path: normalize_entry_path(&path),
+            data_offset: entry.raw_file_position(),
+            size: entry.size(),
+            entry_type,
+            mode: header.mode().unwrap_or(0o644),
+            uid: header.uid().unwrap_or(0),
+            gid: header.gid().unwrap_or(0),
+            username: header.username().ok().flatten().map(String::from),
+            groupname: header.groupname().ok().flatten().map(String::from),
+            mtime: header.mtime().unwrap_or(0),
+            // hard links name another entry of the tar, symlinks a path on the device
+            link_name: entry
+                .link_name()
+                .ok()
+                .flatten()
+                .and_then(|link_name| link_name.to_str().map(String::from))
+                .map(|link_name| match entry_type {
+                    EntryType::Link => normalize_entry_path(&link_name),
+                    _ => link_name,
+                }),
+            xattrs,
+        });
+    }
+    // read the padding after the end-of-archive marker too, so a gzip trailer gets checked
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(TarIndex {
+        tar_path: tar_path.to_path_buf(),
+        entries,
+    })
+}
+
+// TWRP stores "/data/app/...", tars repacked by other tools may hold "data/app/..." or
+// "./data/app/..." instead
+fn normalize_entry_path(path: &str) -> String {
+    let mut relative = path.trim_start_matches('/');
+    while let Some(rest) = relative.strip_prefix("./") {
+        relative = rest.trim_start_matches('/');
+    }
+    if relative == "." {
+        relative = "";
+    }
+    format!("/{}", relative).trim_end_matches('/').to_string()
+}
+
+// positions the tar at an indexed entry's data
+fn read_entry_data<'a>(
+    tar: &'a mut BufReader<File>,
+    entry: &TarIndexEntry,
+) -> Result<io::Take<&'a mut BufReader<File>>, io::Error> {
+    tar.seek(SeekFrom::Start(entry.data_offset))?;
+    Ok(tar.take(entry.size))
+}
+
+fn open_archive(tar_path: &Path, config: &Config) -> Result<Archive<BufReader<File>>, io::Error> {
+    let file = File::open(tar_path)?;
+    Ok(Archive::new(BufReader::with_capacity(
+        config.read_buffer_size,
+        file,
+    )))
+}
+
+// any chunk of the backup may be given, all chunks sharing its prefix are returned in order
+fn find_all_win_files(win_path: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let first_path = Path::new(win_path);
+    // a bare "data.ext4.win000" has an empty parent, it's run from inside the backup folder
+    let parent_dir = match first_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let file_prefix = match first_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(split_win_file_name)
+    {
+        Some((prefix, _)) => prefix,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a TWRP backup file (.win or .winNNN)",
+            ))
+        }
+    };
+
+    // only exact "<prefix>.win" or "<prefix>.winNNN" siblings, so neither data.extra.win000 nor the
+    // data.ext4.win000.sha2 checksum files are picked up
+    let mut win_files: Vec<PathBuf> = fs::read_dir(parent_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(split_win_file_name)
+                .map(|(prefix, _)| prefix == file_prefix)
+                .unwrap_or(false)
+        })
+        .collect();
+
+    sort_win_files(&mut win_files);
+    Ok(win_files)
+}
+
+// "data.ext4.win012" is split into ("data.ext4", 12); an unsplit backup is a single
+// "data.ext4.win", taken as chunk 0. Other names are not chunks
+fn split_win_file_name(file_name: &str) -> Option<(&str, u32)> {
+    let (prefix, suffix) = file_name.rsplit_once(".win")?;
+    if prefix.is_empty() {
+        return None;
+    }
+    match suffix.len() {
+        0 => Some((prefix, 0)),
+        3 if suffix.bytes().all(|b| b.is_ascii_digit()) => Some((prefix, suffix.parse().ok()?)),
+        _ => None,
+    }
+}
+
+// whether the file name is a TWRP backup chunk, i.e. ends with .win or .winNNN
+pub fn is_win_file_name(file_name: &str) -> bool {
+    split_win_file_name(file_name).is_some()
+}
+
+// the first chunk of each data partition backup in a TWRP backup folder, or in the backup folders
+// inside it; system, boot and other partitions are passed over
+pub fn find_data_backups(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut dirs = vec![dir.to_path_buf()];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+
+    let mut backups = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut first_chunks: HashMap<String, (u32, PathBuf)> = HashMap::new();
+        for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+            let Some((prefix, number)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(split_win_file_name)
+            else {
+                continue;
+            };
+            // "data.ext4", "data.f2fs", ...
+            if !prefix.starts_with("data.") {
+                continue;
+            }
+            let first_chunk = first_chunks
+                .entry(prefix.to_string())
+                .or_insert((number, path.clone()));
+            if number < first_chunk.0 {
+                *first_chunk = (number, path);
+            }
+        }
+        backups.extend(first_chunks.into_values().map(|(_, path)| path));
+    }
+
+    backups.sort();
+    Ok(backups)
+}
+
+// picks the backup to migrate when a folder holds several: the newest one, or the one the user
+// chooses
+pub fn choose_data_backup(backups: Vec<PathBuf>, latest: bool) -> Result<PathBuf, io::Error> {
+    match backups.len() {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No data partition backup (data.*.win000 or data.*.win) found in the folder",
+        )),
+        1 => Ok(backups[0].clone()),
+        _ if latest => Ok(backups
+            .into_iter()
+            .max_by_key(|path| {
+                (
+                    backup_time_from_folder_name(path),
+                    fs::metadata(path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok(),
+                )
+            })
+            .unwrap()),
+        _ => {
+            println!("Multiple data backups found:");
+            for (position, path) in backups.iter().enumerate() {
+                println!("  {}: {}", position + 1, path.display());
+            }
+            print!("Enter the number of the backup to migrate (or rerun with --latest): ");
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            io::stdin().lock().read_line(&mut answer)?;
+            let answer = answer.trim();
+            answer
+                .parse::<usize>()
+                .ok()
+                .and_then(|number| backups.get(number.checked_sub(1)?).cloned())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown backup number: {}", answer),
+                    )
+                })
+        }
+    }
+}
+
+// the chunk number of "data.ext4.win012" is 12
+fn win_chunk_number(path: &Path) -> Option<u32> {
+    split_win_file_name(path.file_name()?.to_str()?).map(|(_, number)| number)
+}
+
+// orders chunks by their number, then by name, so the result doesn't depend on the order the
+// filesystem listed them in
+fn sort_win_files(win_files: &mut [PathBuf]) {
+    win_files.sort_by(|a, b| {
+        win_chunk_number(a)
+            .cmp(&win_chunk_number(b))
+            .then_with(|| a.file_name().cmp(&b.file_name()))
+            .then_with(|| a.cmp(b))
+    });
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8; 5] = b"ustar";
+const EXT4_MAGIC_OFFSET: usize = 0x438;
+const EXT4_MAGIC: [u8; 2] = [0x53, 0xef];
+const SPARSE_IMAGE_MAGIC: [u8; 4] = [0x3a, 0xff, 0x26, 0xed];
+// TWRP encrypts backups with OpenAES, whose files start with this
+const OAES_MAGIC: &[u8; 4] = b"OAES";
+
+// explains what to do when handed a filesystem image instead of a TWRP tar backup
+fn filesystem_image_error(win_path: &Path) -> Result<Option<io::Error>, io::Error> {
+    let mut head = Vec::new();
+    File::open(win_path)?
+        .take(EXT4_MAGIC_OFFSET as u64 + 2)
+        .read_to_end(&mut head)?;
+
+    let message = if head.starts_with(&SPARSE_IMAGE_MAGIC) {
+        "it's an Android sparse image, not a TWRP backup. This tool only reads TWRP's tar backups \
+         (.win files); convert it with `simg2img` and mount the resulting raw image \
+         (e.g. `sudo mount -o loop,ro data.raw.img /mnt`) to copy files from it"
+    } else if head.get(EXT4_MAGIC_OFFSET..) == Some(&EXT4_MAGIC[..]) {
+        "it's a raw ext4 filesystem image, not a TWRP backup. This tool only reads TWRP's tar \
+         backups (.win files); make a TWRP backup of Data instead, or mount the image \
+         (e.g. `sudo mount -o loop,ro data.img /mnt`) to copy files from it"
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(io::Error::new(io::ErrorKind::InvalidData, message)))
+}
+
+fn file_digest<D: Digest>(
+    path: &Path,
+    bar: &ProgressBar,
+    config: &Config,
+) -> Result<String, io::Error> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::with_capacity(
+        config.read_buffer_size,
+        InterruptibleRead(bar.wrap_read(file)),
+    );
+    let mut hasher = D::new();
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+        hasher.update(buffer);
+        let len = buffer.len();
+        reader.consume(len);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// TWRP writes "<hex digest>  <file name>" to data.ext4.win000.sha2 (SHA-256) or .md5 next to each
+// chunk; chunks without a digest file are taken as they are
+fn verify_win_file(
+    win_path: &Path,
+    bar: &ProgressBar,
+    config: &Config,
+) -> Result<(), EvacuateError> {
+    let digest_file = ["sha2", "md5"].iter().find_map(|extension| {
+        let mut digest_path = win_path.as_os_str().to_owned();
+        digest_path.push(format!(".{}", extension));
+        let content = fs::read_to_string(digest_path).ok()?;
+        Some((*extension, content))
+    });
+    let Some((digest_kind, content)) = digest_file else {
+        return Ok(());
+    };
+
+    let expected = content
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    let actual = match digest_kind {
+        "sha2" => file_digest::<Sha256>(win_path, bar, config),
+        _ => file_digest::<Md5>(win_path, bar, config),
+    }
+    .map_err(|source| EvacuateError::Io {
+        path: win_path.to_path_buf(),
+        source,
+    })?;
+
+    match actual == expected {
+        true => Ok(()),
+        false => Err(EvacuateError::Checksum {
+            path: win_path.to_path_buf(),
+            digest_kind,
+        }),
+    }
+}
+
+// skips the gzip member header, including the optional extra/name/comment fields
+fn skip_gzip_header(reader: &mut impl BufRead) -> Result<(), io::Error> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    let flags = header[3];
+
+    if flags & 0x04 != 0 {
+        let mut extra_len = [0u8; 2];
+        reader.read_exact(&mut extra_len)?;
+        io::copy(
+            &mut reader.take(u16::from_le_bytes(extra_len) as u64),
+            &mut io::sink(),
+        )?;
+    }
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            reader.read_until(0, &mut Vec::new())?;
+        }
+    }
+    if flags & 0x02 != 0 {
+        reader.read_exact(&mut [0u8; 2])?;
+    }
+
+    Ok(())
+}
+
+// compares the CRC32 and length in the gzip trailer with the decompressed data once the deflate
+// stream has been read to the end; a mismatch is only a warning, so partially recoverable
+// backups still go through
+struct GzipTrailerCheck<R: BufRead> {
+    reader: Option<CrcReader<DeflateDecoder<R>>>,
+    win_path: PathBuf,
+}
+
+impl<R: BufRead> GzipTrailerCheck<R> {
+    fn check_trailer(&self, reader: CrcReader<DeflateDecoder<R>>) {
+        let (crc, length) = (reader.crc().sum(), reader.crc().amount());
+        let mut trailer = [0u8; 8];
+        let problem = match reader.into_inner().into_inner().read_exact(&mut trailer) {
+            Err(_) => Some("gzip trailer is missing, the file is probably truncated"),
+            Ok(()) if read_u32_le(&trailer, 0) != Some(crc) => Some("gzip CRC32 doesn't match"),
+            Ok(()) if read_u32_le(&trailer, 4) != Some(length) => Some("gzip length doesn't match"),
+            Ok(()) => None,
+        };
+        if let Some(problem) = problem {
+            record_warning(format!(
+                "{}: {}, the extracted data may be corrupt",
+                self.win_path.display(),
+                problem
+            ));
+        }
+    }
+}
+
+impl<R: BufRead> Read for GzipTrailerCheck<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(0);
+        };
+        let read = reader.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            if let Some(reader) = self.reader.take() {
+                self.check_trailer(reader);
+            }
+        }
+        Ok(read)
+    }
+}
+
+// the tar stream inside a backup chunk, which is either gzip-compressed or a plain tar
+fn win_file_reader<'a>(
+    mut file: impl BufRead + 'a,
+    win_path: &Path,
+    config: &Config,
+) -> Result<Box<dyn Read + 'a>, io::Error> {
+    let head = file.fill_buf()?;
+    let is_gzip = head.starts_with(&GZIP_MAGIC);
+    let is_tar = head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC);
+    let is_encrypted = head.starts_with(OAES_MAGIC);
+
+    match (is_gzip, is_tar) {
+        (true, _) => {
+            // skip gzip header (crc checksum) in case of corrupted files
+            skip_gzip_header(&mut file)?;
+
+            // decompress deflate stream directly, stored (uncompressed) blocks included
+            let decoder = DeflateDecoder::new(file);
+            match config.verify_checksums {
+                true => Ok(Box::new(GzipTrailerCheck {
+                    reader: Some(CrcReader::new(decoder)),
+                    win_path: win_path.to_path_buf(),
+                })),
+                false => Ok(Box::new(decoder)),
+            }
+        }
+        // TWRP backups made with compression disabled are plain tars
+        (false, true) => Ok(Box::new(file)),
+        (false, false) => {
+            if is_encrypted {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the backup is encrypted. Restore it with TWRP using its password, or \
+                     decrypt each chunk first with `openaes dec --key <password> --in <chunk> \
+                     --out <decrypted chunk>` (TWRP's openaes tool) and run this on the decrypted \
+                     chunks",
+                ));
+            }
+            if let Some(e) = filesystem_image_error(win_path)? {
+                return Err(e);
+            }
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "it's neither a gzip-compressed nor a plain tar TWRP backup. If it was made with \
+                 encryption enabled, decrypt it first",
+            ))
+        }
+    }
+}
+
+// a handful of entries from the start of the first chunk tell a data partition backup from a
+// system, vendor, ... one, before the whole backup gets decompressed for nothing
+const SAMPLED_ENTRIES: usize = 64;
+
+// the partition a backup chunk holds when it isn't data, e.g. "system"; unreadable chunks are
+// left to the decompression to report
+fn other_partition_name(win_path: &Path, config: &Config) -> Option<String> {
+    let file = BufReader::with_capacity(config.read_buffer_size, File::open(win_path).ok()?);
+    let mut archive = Archive::new(win_file_reader(file, win_path, config).ok()?);
+    let sampled_paths: Vec<PathBuf> = archive
+        .entries()
+        .ok()?
+        .take(SAMPLED_ENTRIES)
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path().ok()?;
+            Some(PathBuf::from(normalize_entry_path(path.to_str()?)))
+        })
+        .collect();
+    if sampled_paths.iter().any(|path| path.starts_with("/data")) {
+        return None;
+    }
+
+    sampled_paths
+        .first()?
+        .components()
+        .find_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+}
+
+fn check_data_partition(path: &Path, config: &Config) -> Result<(), EvacuateError> {
+    match other_partition_name(path, config) {
+        Some(partition) => Err(EvacuateError::Io {
+            path: path.to_path_buf(),
+            source: io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "it's a backup of the {} partition, not of data (no /data/data or /data/app \
+                     in it). Pass the data.*.win000 file of the backup instead, or the backup \
+                     folder",
+                    partition
+                ),
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// --from-tar takes the tar TWRP's backup files hold, already decompressed
+fn check_plain_tar(tar_path: &Path) -> Result<(), io::Error> {
+    let mut head = Vec::new();
+    File::open(tar_path)?
+        .take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+        .read_to_end(&mut head)?;
+    match head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC) {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a plain, uncompressed tar. Pass TWRP's backup files as the input instead of \
+             using --from-tar",
+        )),
+    }
+}
+
+// index is the chunk's position in the backup, it keeps the tar names unique
+fn decompress_win_file(
+    win_path: &Path,
+    index: usize,
+    bar: &ProgressBar,
+    config: &Config,
+) -> Result<PathBuf, EvacuateError> {
+    let decompress_error = |source| EvacuateError::Decompress {
+        path: win_path.to_path_buf(),
+        source,
+    };
+    let file = File::open(win_path).map_err(decompress_error)?;
+    bar.inc_length(file.metadata().map_err(decompress_error)?.len());
+    let file = BufReader::with_capacity(
+        config.read_buffer_size,
+        InterruptibleRead(bar.wrap_read(file)),
+    );
+    let mut reader = win_file_reader(file, win_path, config).map_err(decompress_error)?;
+
+    let tar_dir = format!("{}/{}", config.output_dir, DECOMPRESSED_TAR_DIR);
+    fs::create_dir_all(&tar_dir).map_err(decompress_error)?;
+
+    let win_name = win_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let tar_path = format!("{}/{:03}-{}.tar", tar_dir, index, win_name);
+    info!("Decompressing {} to {}", win_path.display(), tar_path);
+    // streamed to disk, a chunk can decompress to several GB
+    let mut tar_file = BufWriter::with_capacity(
+        config.read_buffer_size,
+        File::create(&tar_path).map_err(decompress_error)?,
+    );
+    io::copy(&mut reader, &mut tar_file).map_err(decompress_error)?;
+    tar_file.flush().map_err(decompress_error)?;
+
+    Ok(tar_path.into())
+}
+
+// install dirs holding a base.apk in any of the backup's chunks, their split APKs may sit in
+// other chunks
+fn find_all_apks(tar_indexes: &[TarIndex], config: &Config) -> Vec<ApkFsItem> {
+    let mut apks: Vec<ApkFsItem> = tar_indexes
+        .iter()
+        .flat_map(|index| index.entries_under("/data/app"))
+        .map(|entry| entry.path.as_str())
+        .filter(|path_str| path_str.ends_with("/base.apk"))
+        .filter_map(|path_str| {
+            let mut parts = path_str.split('/');
+            let root_dir_name = parts.nth(3);
+            let instance_dir_name = parts.next();
+
+            match instance_dir_name?.ends_with(".apk") {
+                true => Some(ApkFsItem {
+                    root_dir_name: None,
+                    instance_dir_name: root_dir_name?.to_string(),
+                }),
+                false => Some(ApkFsItem {
+                    root_dir_name: root_dir_name.map(String::from),
+                    instance_dir_name: instance_dir_name?.to_string(),
+                }),
+            }
+        })
+        .filter(|apk| {
+            let package_name = apk.package_name();
+            if !is_safe_package_name(package_name) {
+                record_warning(format!(
+                    "Skipped APK with unsafe package name: {:?}",
+                    package_name
+                ));
+                return false;
+            }
+            is_selected_package(package_name, config)
+        })
+        .collect();
+    apks.sort();
+    apks.dedup();
+    apks
+}
+
+// copies base.apk and the split APKs (split_config.arm64_v8a.apk, ...) of an install dir, from
+// whichever chunks hold them
+fn extract_apks_to_temp(
+    tar_indexes: &[TarIndex],
+    apk: &ApkFsItem,
+    config: &Config,
+) -> Result<Vec<PathBuf>, io::Error> {
+    let package_name = apk.package_name().to_string();
+    let apk_dir_path = apk.dir_path();
+    // each install dir gets its own folder so stale installs don't mix with the current one
+    let dest_dir = format!(
+        "{}/{}/{}/{}",
+        config.output_dir, APK_TEMP_DIR, package_name, apk.instance_dir_name
+    );
+
+    fs::create_dir_all(&dest_dir)?;
+
+    let _reader_slot = config.tar_reader_slots.acquire();
+    let mut apk_paths = Vec::new();
+    for index in tar_indexes {
+        let mut tar =
+            BufReader::with_capacity(config.read_buffer_size, File::open(&index.tar_path)?);
+        // only the APKs right in the install dir, pm installs them together as one app
+        let entries = index
+            .entries_under(&apk_dir_path)
+            .into_iter()
+            .filter(|entry| {
+                entry.path.ends_with(".apk")
+                    && entry.entry_type.is_file()
+                    && Path::new(&entry.path).parent() == Some(Path::new(&apk_dir_path))
+            });
+        for entry in entries {
+            let Some(file_name) = Path::new(&entry.path).file_name() else {
+                continue;
+            };
+            let dest_path = Path::new(&dest_dir).join(file_name);
+            let mut dest_file = File::create(&dest_path)?;
+            io::copy(&mut read_entry_data(&mut tar, entry)?, &mut dest_file)?;
+            // keep the install time so the newest install can be told apart later
+            let result =
+                dest_file.set_modified(time::UNIX_EPOCH + time::Duration::from_secs(entry.mtime));
+            if !config.shared_storage {
+                result?;
+            }
+            apk_paths.push(dest_path);
+        }
+    }
+
+    Ok(apk_paths)
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64_le(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+// splits a sequence of u32-length-prefixed values, the encoding used all over the APK signing block
+fn length_prefixed_items(mut bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut items = Vec::new();
+    while !bytes.is_empty() {
+        let len = read_u32_le(bytes, 0)? as usize;
+        items.push(bytes.get(4..4 + len)?);
+        bytes = &bytes[4 + len..];
+    }
+    Some(items)
+}
+
+fn invalid_apk(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// https://source.android.com/docs/security/features/apksigning/v2#apk-signing-block
+const APK_SIG_BLOCK_MAGIC: &[u8; 16] = b"APK Sig Block 42";
+const APK_SIGNATURE_SCHEME_V2_ID: u32 = 0x7109871a;
+const APK_SIGNATURE_SCHEME_V3_ID: u32 = 0xf05368c0;
+// signature algorithms whose content digest is the chunked SHA-256
+const APK_SHA256_DIGEST_ALGORITHMS: [u32; 4] = [0x0101, 0x0103, 0x0201, 0x0301];
+const APK_DIGEST_CHUNK_SIZE: u64 = 1024 * 1024;
+
+fn first_length_prefixed(bytes: &[u8]) -> Option<&[u8]> {
+    let len = read_u32_le(bytes, 0)? as usize;
+    bytes.get(4..4 + len)
+}
+
+// returns the expected chunked SHA-256 digest of the first signer in a v2/v3 signature scheme block
+fn find_apk_sha256_digest(scheme_block: &[u8]) -> Option<Vec<u8>> {
+    let signers = length_prefixed_items(first_length_prefixed(scheme_block)?)?;
+    let signed_data = first_length_prefixed(signers.first()?)?;
+    let digests = length_prefixed_items(first_length_prefixed(signed_data)?)?;
+
+    digests.into_iter().find_map(|digest| {
+        let algorithm = read_u32_le(digest, 0)?;
+        match APK_SHA256_DIGEST_ALGORITHMS.contains(&algorithm) {
+            true => first_length_prefixed(digest.get(4..)?).map(|v| v.to_vec()),
+            false => None,
+        }
+    })
+}
+
+fn digest_apk_section(
+    file: &mut File,
+    start: u64,
+    end: u64,
+    chunk_digests: &mut Vec<u8>,
+    chunk_count: &mut u32,
+) -> Result<(), io::Error> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut chunk = vec![0u8; APK_DIGEST_CHUNK_SIZE as usize];
+    let mut offset = start;
+    while offset < end {
+        let len = (end - offset).min(APK_DIGEST_CHUNK_SIZE) as usize;
+        file.read_exact(&mut chunk[..len])?;
+        chunk_digests.extend(chunk_digest(&chunk[..len]));
+        *chunk_count += 1;
+        offset += len as u64;
+    }
+    Ok(())
+}
+
+fn chunk_digest(chunk: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([0xa5]);
+    hasher.update((chunk.len() as u32).to_le_bytes());
+    hasher.update(chunk);
+    hasher.finalize().to_vec()
+}
+
+fn verify_apk(apk_path: &Path) -> Result<(), io::Error> {
+    let mut file = File::open(apk_path)?;
+    let file_size = file.metadata()?.len();
+
+    // end of central directory: 22 bytes plus a comment of up to 64 KiB
+    let tail_size = file_size.min(22 + 0xffff);
+    let mut tail = vec![0u8; tail_size as usize];
+    file.seek(SeekFrom::Start(file_size - tail_size))?;
+    file.read_exact(&mut tail)?;
+    let eocd_pos = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&pos| read_u32_le(&tail, pos) == Some(0x06054b50))
+        .ok_or_else(|| invalid_apk("end of central directory not found, the APK is truncated"))?;
+    let mut eocd = tail[eocd_pos..].to_vec();
+    let eocd_offset = file_size - tail_size + eocd_pos as u64;
+
+    let cd_size = read_u32_le(&eocd, 12).unwrap_or(0) as u64;
+    let cd_offset = read_u32_le(&eocd, 16).unwrap_or(0) as u64;
+    if cd_offset == 0xffffffff {
+        // zip64, the 32-bit fields can't be checked
+        return Ok(());
+    }
+    if cd_offset + cd_size != eocd_offset {
+        return Err(invalid_apk("central directory doesn't end where expected"));
+    }
+    let mut cd_signature = [0u8; 4];
+    file.seek(SeekFrom::Start(cd_offset))?;
+    file.read_exact(&mut cd_signature)?;
+    if cd_size > 0 && u32::from_le_bytes(cd_signature) != 0x02014b50 {
+        return Err(invalid_apk("central directory is corrupt"));
+    }
+
+    // APK signing block, right before the central directory
+    if cd_offset < 32 {
+        return Ok(());
+    }
+    let mut footer = [0u8; 24];
+    file.seek(SeekFrom::Start(cd_offset - 24))?;
+    file.read_exact(&mut footer)?;
+    if &footer[8..] != APK_SIG_BLOCK_MAGIC {
+        // no v2/v3 signature, the zip structure is all we can check
+        return Ok(());
+    }
+    let block_size = read_u64_le(&footer, 0).unwrap_or(0);
+    let block_start = cd_offset
+        .checked_sub(block_size.saturating_add(8))
+        .filter(|_| block_size >= 24)
+        .ok_or_else(|| invalid_apk("APK signing block is corrupt"))?;
+    let mut block = vec![0u8; (block_size - 24) as usize];
+    file.seek(SeekFrom::Start(block_start + 8))?;
+    file.read_exact(&mut block)?;
+
+    let mut pairs = HashMap::new();
+    let mut pos = 0;
+    while pos + 12 <= block.len() {
+        let len = read_u64_le(&block, pos).unwrap_or(0) as usize;
+        let id = read_u32_le(&block, pos + 8).unwrap_or(0);
+        let value = block
+            .get(pos + 12..pos + 8 + len)
+            .ok_or_else(|| invalid_apk("APK signing block is corrupt"))?;
+        pairs.insert(id, value);
+        pos += 8 + len;
+    }
+    let expected_digest = [APK_SIGNATURE_SCHEME_V2_ID, APK_SIGNATURE_SCHEME_V3_ID]
+        .iter()
+        .filter_map(|id| pairs.get(id))
+        .find_map(|scheme_block| find_apk_sha256_digest(scheme_block));
+    let Some(expected_digest) = expected_digest else {
+        return Ok(());
+    };
+
+    // the digest covers the zip entries, the central directory and the EOCD, the latter with its
+    // central directory offset pointing at the start of the signing block
+    let mut chunk_digests = Vec::new();
+    let mut chunk_count = 0;
+    digest_apk_section(
+        &mut file,
+        0,
+        block_start,
+        &mut chunk_digests,
+        &mut chunk_count,
+    )?;
+    digest_apk_section(
+        &mut file,
+        cd_offset,
+        eocd_offset,
+        &mut chunk_digests,
+        &mut chunk_count,
+    )?;
+    eocd[16..20].copy_from_slice(&(block_start as u32).to_le_bytes());
+    for eocd_chunk in eocd.chunks(APK_DIGEST_CHUNK_SIZE as usize) {
+        chunk_digests.extend(chunk_digest(eocd_chunk));
+        chunk_count += 1;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update([0x5a]);
+    hasher.update(chunk_count.to_le_bytes());
+    hasher.update(&chunk_digests);
+    match hasher.finalize().as_slice() == expected_digest.as_slice() {
+        true => Ok(()),
+        false => Err(invalid_apk("content doesn't match the signature digest")),
+    }
+}
+
+// every /data/user/<id>: secondary users and work profiles (10, 11, ...) as well as the clone
+// users some OEMs add for dual apps (95, 999, ...)
+fn find_all_users(index: &TarIndex) -> Vec<UserId> {
+    let mut user_ids: Vec<UserId> = index
+        .entries_under("/data/user")
+        .into_iter()
+        .filter_map(|entry| entry.path.split('/').nth(3)?.parse::<UserId>().ok())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    user_ids.sort();
+    user_ids
+}
+
+// packages with a base.apk under /data/app, i.e. everything but system apps
+fn find_installed_packages(index: &TarIndex) -> HashSet<PackageName> {
+    index
+        .entries_under("/data/app")
+        .into_iter()
+        .filter(|entry| entry.path.ends_with("/base.apk"))
+        .filter_map(|entry| {
+            let instance_dir_name = entry.path.rsplit('/').nth(1)?;
+            Some(instance_dir_package_name(instance_dir_name).to_string())
+        })
+        .collect()
+}
+
+fn scan_backup_scope(
+    index: &TarIndex,
+    user_ids: &[UserId],
+    data_kinds: &[DataKind],
+    scope: &mut BackupScope,
+    config: &Config,
+) {
+    for entry in &index.entries {
+        let parts: Vec<&str> = entry.path.split('/').collect();
+
+        let (user_id, package_name) = match parts.as_slice() {
+            ["", "data", "app", .., instance_dir_name, "base.apk"] => {
+                let package_name = instance_dir_package_name(instance_dir_name);
+                if is_safe_package_name(package_name) && is_selected_package(package_name, config) {
+                    scope.apk_packages.insert(package_name.to_string());
+                    if !config.data_only {
+                        scope.size += entry.size;
+                    }
+                }
+                continue;
+            }
+            ["", "data", "app", ..] => {
+                // one of the components is the "<package>-<suffix>" instance dir
+                if !config.data_only
+                    && parts
+                        .iter()
+                        .any(|part| is_selected_package(instance_dir_package_name(part), config))
+                {
+                    scope.size += entry.size;
+                }
+                continue;
+            }
+            ["", "data", "data", package_name, ..] => (0, *package_name),
+            ["", "data", "user" | "user_de", user_id, package_name, ..] => {
+                match user_id.parse::<UserId>() {
+                    Ok(user_id) => (user_id, *package_name),
+                    Err(_) => continue,
+                }
+            }
+            // OBB and app files on shared storage, counted when they are migrated
+            ["", "data", "media", user_id, "Android", dir_name, package_name, ..] => {
+                let kind = match *dir_name {
+                    "obb" => DataKind::Obb,
+                    "data" => DataKind::External,
+                    "media" => DataKind::Media,
+                    _ => continue,
+                };
+                match user_id.parse::<UserId>() {
+                    Ok(user_id) if data_kinds.contains(&kind) => (user_id, *package_name),
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+
+        if !user_ids.contains(&user_id) || !is_safe_package_name(package_name) {
+            continue;
+        }
+        if is_skipped_system_app(package_name, config) {
+            scope
+                .skipped_system_packages
+                .insert(package_name.to_string());
+        } else if config.apk_backups {
+            // only the APK is migrated, the data of system apps isn't
+            if is_selected_package(package_name, config)
+                && config.installed_packages.contains(package_name)
+            {
+                scope
+                    .packages
+                    .entry(user_id)
+                    .or_default()
+                    .insert(package_name.to_string());
+            }
+        } else if is_selected_package(package_name, config) {
+            scope
+                .packages
+                .entry(user_id)
+                .or_default()
+                .insert(package_name.to_string());
+            scope.size += entry.size;
+        }
+    }
+}
+
+// the kinds of per-package data in a backup, each one gets its own archive
+#[derive(Clone, Copy, PartialEq)]
+enum DataKind {
+    App,
+    DeviceProtected,
+    Obb,
+    External,
+    Media,
+}
+
+impl DataKind {
+    const ALL: [DataKind; 5] = [
+        DataKind::App,
+        DataKind::DeviceProtected,
+        DataKind::Obb,
+        DataKind::External,
+        DataKind::Media,
+    ];
+
+    // archive name Neo Backup expects in a backup folder, before compression
+    fn archive_name(self) -> &'static str {
+        match self {
+            DataKind::App => "data.tar",
+            DataKind::DeviceProtected => "device_protected_files.tar",
+            DataKind::Obb => "obb_files.tar",
+            DataKind::External => "external_files.tar",
+            DataKind::Media => "media_files.tar",
+        }
+    }
+
+    fn compressed_archive_name(self, extension: &str) -> String {
+        format!("{}.{}", self.archive_name(), extension)
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            DataKind::App => "app data",
+            DataKind::DeviceProtected => "device protected data",
+            DataKind::Obb => "OBB files",
+            DataKind::External => "external data",
+            DataKind::Media => "media files",
+        }
+    }
+}
+
+// where a user's app data lives; device protected data may be spread over several vendor layouts
+fn app_data_base_paths(user_id: UserId, kind: DataKind, config: &Config) -> Vec<String> {
+    match (kind, user_id) {
+        (DataKind::DeviceProtected, _) => config
+            .de_path_templates
+            .iter()
+            .map(|template| template.replace("{user}", &user_id.to_string()))
+            .collect(),
+        // shared storage, /storage/emulated/<user> on the device
+        (DataKind::Obb, _) => vec![format!("/data/media/{}/Android/obb", user_id)],
+        (DataKind::External, _) => vec![format!("/data/media/{}/Android/data", user_id)],
+        (DataKind::Media, _) => vec![format!("/data/media/{}/Android/media", user_id)],
+        (DataKind::App, 0) => vec!["/data/data".to_string()],
+        (DataKind::App, _) => vec![format!("/data/user/{}", user_id)],
+    }
+}
+
+// finds "/data/<name>_de/<user>" directories whose subdirectories are named like the packages in
+// /data/data, for ROMs keeping device protected data outside of /data/user_de
+fn detect_de_path_templates(index: &TarIndex, config: &Config) -> Vec<String> {
+    let mut ce_packages = HashSet::new();
+    let mut de_candidates: HashMap<String, HashSet<String>> = HashMap::new();
+    for entry in index.entries_under("/data") {
+        match entry.path.split('/').collect::<Vec<_>>().as_slice() {
+            ["", "data", "data", package_name, ..] => {
+                ce_packages.insert(package_name.to_string());
+            }
+            ["", "data", de_dir, user_id, package_name, ..]
+                if de_dir.ends_with("_de") && user_id.parse::<UserId>().is_ok() =>
+            {
+                de_candidates
+                    .entry(format!("/data/{}/{{user}}", de_dir))
+                    .or_default()
+                    .insert(package_name.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    let mut templates: Vec<String> = de_candidates
+        .into_iter()
+        .filter(|(template, _)| !config.de_path_templates.contains(template))
+        .filter(|(_, package_names)| package_names.iter().any(|p| ce_packages.contains(p)))
+        .map(|(template, _)| template)
+        .collect();
+    templates.sort();
+    templates
+}
+
+fn find_all_app_data(
+    index: &TarIndex,
+    user_id: UserId,
+    kind: DataKind,
+    config: &Config,
+) -> Vec<PackageName> {
+    let mut package_names: Vec<String> = app_data_base_paths(user_id, kind, config)
+        .iter()
+        .flat_map(|base_path| {
+            index
+                .entries_under(base_path)
+                .into_iter()
+                .filter_map(move |entry| {
+                    let relative_path = entry.path.strip_prefix(base_path.as_str())?;
+                    let relative_path = relative_path.strip_prefix('/')?;
+                    relative_path.split('/').next().map(|part| part.to_string())
+                })
+        })
+        .filter(|package_name| !package_name.is_empty())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter(|package_name| {
+            if !is_safe_package_name(package_name) {
+                record_warning(format!(
+                    "Skipped app data with unsafe package name: {:?}",
+                    package_name
+                ));
+                return false;
+            }
+            is_selected_package(package_name, config)
+        })
+        .collect();
+
+    package_names.sort();
+    package_names
+}
+
+// with --apk-backups, the packages of a user that get a backup: those installed for them, i.e.
+// with app data, and with an APK in /data/app
+fn find_apk_backup_packages(
+    tar_indexes: &[TarIndex],
+    user_id: UserId,
+    config: &Config,
+) -> HashSet<PackageName> {
+    tar_indexes
+        .iter()
+        .flat_map(|tar_index| find_all_app_data(tar_index, user_id, DataKind::App, config))
+        .filter(|package_name| config.installed_packages.contains(package_name))
+        .collect()
+}
+
+// Android's backup agent leaves these out too, they are rebuilt by the app or not meant to move
+// to another device
+fn is_cache_entry(entry: &TarIndexEntry, relative_path: &Path) -> bool {
+    let is_cache_dir = relative_path.components().next().is_some_and(|dir| {
+        EXCLUDED_APP_DATA_DIRS
+            .iter()
+            .any(|excluded| dir.as_os_str() == *excluded)
+    });
+    // cache files are usually owned by the app's <user>_cache group, even outside cache/
+    let is_cache_group = entry
+        .groupname
+        .as_ref()
+        .is_some_and(|groupname| groupname.ends_with("_cache"));
+    match (is_cache_dir, is_cache_group) {
+        (true, _) => debug!(
+            "Skipping {}, it is in a cache or no_backup directory",
+            entry.path
+        ),
+        (false, true) => debug!("Skipping {}, it belongs to a cache group", entry.path),
+        (false, false) => {}
+    }
+    is_cache_dir || is_cache_group
+}
+
+// Android ids are <user> * 100000 + <app id>; an app's files are owned by its uid (app ids
+// 10000-19999, "u0_a123"), its cache files by its cache gid (20000-29999, "u0_a123_cache") and
+// shared ones by its shared gid (50000-59999, "all_a123"). These are assigned per install and
+// differ between devices, so each range is mapped to its first id; other ids are kept
+const PER_USER_RANGE: u64 = 100000;
+const APP_ID_RANGES: [(u64, u64); 3] = [(10000, 19999), (20000, 29999), (50000, 59999)];
+
+fn normalized_owner_id(id: u64) -> u64 {
+    let (user_id, app_id) = (id / PER_USER_RANGE, id % PER_USER_RANGE);
+    APP_ID_RANGES
+        .iter()
+        .find(|(first, last)| (*first..=*last).contains(&app_id))
+        .map_or(id, |(first, _)| user_id * PER_USER_RANGE + first)
+}
+
+// the name Android gives a normalized id, so the cache group still reads as one
+fn normalized_owner_name(id: u64) -> Option<String> {
+    let (user_id, app_id) = (id / PER_USER_RANGE, id % PER_USER_RANGE);
+    match app_id {
+        10000 => Some(format!("u{}_a0", user_id)),
+        20000 => Some(format!("u{}_a0_cache", user_id)),
+        50000 => Some("all_a0".to_string()),
+        _ => None,
+    }
+}
+
+fn extract_app_data(
+    index: &TarIndex,
+    user_id: UserId,
+    package_name: &PackageName,
+    kind: DataKind,
+    bar: &ProgressBar,
+    config: &Config,
+) -> Result<(), EvacuateError> {
+    let package_error = |source| EvacuateError::Package {
+        user_id,
+        package_name: package_name.clone(),
+        source,
+    };
+    let data_paths: Vec<PathBuf> = app_data_base_paths(user_id, kind, config)
+        .into_iter()
+        .map(|base_path| Path::new(&base_path).join(package_name))
+        .collect();
+    let dest_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+
+    let win_tar_file_name = index
+        .tar_path
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let dest_tar_path = format!("{}/{}-{}", dest_dir, win_tar_file_name, kind.archive_name());
+
+    let mut entries: Vec<(&TarIndexEntry, PathBuf)> = data_paths
+        .iter()
+        .flat_map(|data_path| {
+            index
+                .entries_under(&data_path.to_string_lossy())
+                .into_iter()
+                .filter_map(move |entry| {
+                    let relative_path = Path::new(&entry.path).strip_prefix(data_path).ok()?;
+                    Some((entry, relative_path.to_path_buf()))
+                })
+        })
+        .filter(|(entry, relative_path)| {
+            // OBB and media files are plain files on shared storage, there is no app cache among
+            // them
+            matches!(kind, DataKind::Obb | DataKind::Media)
+                || config.include_cache
+                || !is_cache_entry(entry, relative_path)
+        })
+        .filter(|(entry, relative_path)| {
+            let excluded = config
+                .exclude_patterns
+                .iter()
+                .any(|pattern| pattern.matches_path(relative_path));
+            if excluded {
+                debug!("Skipping {}, it matches --exclude-pattern", entry.path);
+            }
+            !excluded
+        })
+        .collect();
+    // read the tar front to back
+    entries.sort_by_key(|(entry, _)| entry.data_offset);
+    bar.inc_length(entries.iter().map(|(entry, _)| entry.size).sum());
+    let kept_paths: HashSet<&str> = entries
+        .iter()
+        .map(|(entry, _)| entry.path.as_str())
+        .collect();
+
+    // the archive slot first, merge_tar_files holds one without reading the tars
+    let _slot = config.archive_slots.acquire();
+    let _reader_slot = config.tar_reader_slots.acquire();
+    let mut tar = BufReader::with_capacity(
+        config.read_buffer_size,
+        File::open(&index.tar_path).map_err(package_error)?,
+    );
+    fs::create_dir_all(&dest_dir).map_err(package_error)?;
+    let dest_tar_file = File::create(&dest_tar_path).map_err(package_error)?;
+    let mut dest_tar = tar::Builder::new(dest_tar_file);
+
+    // directories are entries like any other, so empty ones (a fresh files/) are kept and so are
+    // their modes (shared_prefs/ at 0771), the app finds them as it left them
+    for (entry, relative_path) in entries {
+        check_interrupted().map_err(package_error)?;
+        let new_path = Path::new(".").join(relative_path);
+
+        let mut header = Header::new_gnu();
+        header.set_size(entry.size);
+        header.set_entry_type(entry.entry_type);
+        header.set_mode(entry.mode);
+        // kept as recorded unless normalized, for shared storage too (media_rw or ext_data_rw
+        // there); Android's storage daemon assigns its own owners when files are restored through it
+        let (uid, gid, username, groupname) = match config.normalize_owner {
+            true => {
+                let (uid, gid) = (
+                    normalized_owner_id(entry.uid),
+                    normalized_owner_id(entry.gid),
+                );
+                (
+                    uid,
+                    gid,
+                    normalized_owner_name(uid).or_else(|| entry.username.clone()),
+                    normalized_owner_name(gid).or_else(|| entry.groupname.clone()),
+                )
+            }
+            false => (
+                entry.uid,
+                entry.gid,
+                entry.username.clone(),
+                entry.groupname.clone(),
+            ),
+        };
+        header.set_uid(uid);
+        header.set_gid(gid);
+        if let Some(username) = &username {
+            header.set_username(username).map_err(package_error)?;
+        }
+        if let Some(groupname) = &groupname {
+            header.set_groupname(groupname).map_err(package_error)?;
+        }
+        header.set_mtime(entry.mtime);
+
+        // the target of a hard link is another entry of the backup, which now sits at a new path too
+        let hard_link_target = match (entry.entry_type, &entry.link_name) {
+            (EntryType::Link, Some(link_name)) => {
+                let Some(target) = data_paths
+                    .iter()
+                    .find_map(|data_path| Path::new(link_name).strip_prefix(data_path).ok())
+                else {
+                    record_warning(format!(
+                        "Skipped hard link {} of {} (user {}), its target {} is outside the app's data",
+                        entry.path, package_name, user_id, link_name
+                    ));
+                    continue;
+                };
+                Some(target)
+            }
+            _ => None,
+        };
+
+        // the target was left out (cache, --exclude-pattern), so the link gets its content
+        // instead of pointing at nothing
+        let materialized_target = match (&entry.link_name, hard_link_target) {
+            (Some(link_name), Some(_)) if !kept_paths.contains(link_name.as_str()) => {
+                index.find(link_name)
+            }
+            _ => None,
+        };
+
+        // SELinux labels and capabilities among them keep restored files working for their app
+        let size = materialized_target.map_or(entry.size, |target_entry| target_entry.size);
+        append_pax_header(&entry.xattrs, size, &mut dest_tar, config).map_err(package_error)?;
+
+        // links carry their target in the header and have no body
+        match (
+            entry.entry_type,
+            &entry.link_name,
+            hard_link_target,
+            materialized_target,
+        ) {
+            (EntryType::Symlink, Some(link_name), _, _) => {
+                header.set_size(0);
+                dest_tar
+                    .append_link(&mut header, new_path, link_name)
+                    .map_err(package_error)?;
+            }
+            (EntryType::Link, _, _, Some(target_entry)) => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(target_entry.size);
+                dest_tar
+                    .append_data(
+                        &mut header,
+                        new_path,
+                        read_entry_data(&mut tar, target_entry).map_err(package_error)?,
+                    )
+                    .map_err(package_error)?;
+            }
+            (EntryType::Link, _, Some(target), None) => {
+                header.set_size(0);
+                dest_tar
+                    .append_link(&mut header, new_path, target)
+                    .map_err(package_error)?;
+            }
+            _ => dest_tar
+                .append_data(
+                    &mut header,
+                    new_path,
+                    read_entry_data(&mut tar, entry).map_err(package_error)?,
+                )
+                .map_err(package_error)?,
+        }
+        bar.inc(entry.size);
+    }
+
+    dest_tar.finish().map_err(package_error)?;
+
+    Ok(())
+}
+
+fn find_all_extracted_apps(
+    user_id: UserId,
+    config: &Config,
+) -> Result<Vec<PackageName>, io::Error> {
+    let all_app_dir = format!("{}/{}", config.output_dir, user_id);
+
+    let mut extracted_apps = Vec::new();
+    for entry in fs::read_dir(&all_app_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            let package_name = entry.file_name().to_string_lossy().to_string();
+            extracted_apps.push(package_name);
+        }
+    }
+
+    extracted_apps.sort();
+    Ok(extracted_apps)
+}
+
+// picks one install dir when a package was installed more than once (e.g. a leftover of an
+// Android upgrade), so APKs of two versions don't get mixed: the one with the highest
+// versionCode, then the newest base.apk, as file times can't be kept on shared storage
+fn choose_apk_instance_dir(apk_temp_dir: &Path) -> Result<Option<PathBuf>, io::Error> {
+    let instance_dir = fs::read_dir(apk_temp_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter_map(|path| {
+            let base_apk_path = path.join("base.apk");
+            let modified = fs::metadata(&base_apk_path).ok()?.modified().ok()?;
+            let version_code = read_apk_manifest(&base_apk_path)
+                .ok()
+                .and_then(|manifest| manifest.version_code);
+            Some((version_code, modified, path))
+        })
+        .max()
+        .map(|(_, _, path)| path);
+
+    Ok(instance_dir)
+}
+
+fn move_apks_to_destination(
+    user_id: UserId,
+    package_name: &PackageName,
+    config: &Config,
+) -> Result<(), EvacuateError> {
+    let package_error = |source| EvacuateError::Package {
+        user_id,
+        package_name: package_name.clone(),
+        source,
+    };
+    let app_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+    let apk_temp_dir = format!("{}/{}/{}", config.output_dir, APK_TEMP_DIR, package_name);
+
+    if !Path::new(&app_dir).exists() || !Path::new(&apk_temp_dir).exists() {
+        return Ok(());
+    }
+
+    if let Some(instance_dir) =
+        choose_apk_instance_dir(Path::new(&apk_temp_dir)).map_err(package_error)?
+    {
+        fs::read_dir(instance_dir)
+            .map_err(package_error)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("apk"))
+            .try_for_each(|entry| {
+                let dest_path = format!("{}/{}", app_dir, entry.file_name().to_string_lossy());
+                fs::rename(entry.path(), dest_path)
+            })
+            .map_err(package_error)?;
+    }
+
+    Ok(())
+}
+
+// TWRP names backup folders after the time the backup was made, e.g.
+// "2024-11-13--10-13-38_QQ3A200905001", which unlike the chunks' mtime survives copying them
+fn backup_time_from_folder_name(win_path: &Path) -> Option<NaiveDateTime> {
+    let win_path = fs::canonicalize(win_path).ok()?;
+    let folder_name = win_path.parent()?.file_name()?.to_str()?;
+    NaiveDateTime::parse_from_str(folder_name.get(..20)?, "%Y-%m-%d--%H-%M-%S").ok()
+}
+
+fn get_backup_time(win_path: &Path, timezone: Timezone) -> Result<SystemTime, io::Error> {
+    if let Some(backup_time) = backup_time_from_folder_name(win_path)
+        .and_then(|datetime| timezone.to_system_time(&datetime))
+    {
+        return Ok(backup_time);
+    }
+    warn!(
+        "The backup folder isn't named like TWRP names it, using the time {} was last modified",
+        win_path.display()
+    );
+    let file = File::open(win_path)?;
+    let last_modified_time = file.metadata()?.modified()?;
+    Ok(last_modified_time)
+}
+
+fn make_neo_backup_properties(
+    user_id: UserId,
+    package_name: &PackageName,
+    backup_time: SystemTime,
+    label_resolver: &dyn LabelResolver,
+    apk_infos: &ApkInfoCache,
+    config: &Config,
+) -> NeoBackupPropertiesFile {
+    // https://github.com/NeoApplications/Neo-Backup/blob/main/TROUBLESHOOTING.md#faking-properties-files-if-they-are-missing-or-damaged
+    let app_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+
+    let has_apk = Path::new(&app_dir).join("base.apk").exists();
+    let has_archive = |kind: DataKind| {
+        Path::new(&app_dir)
+            .join(kind.compressed_archive_name(config.compression.extension()))
+            .exists()
+    };
+    let has_app_data = has_archive(DataKind::App);
+    let has_devices_protected_data = has_archive(DataKind::DeviceProtected);
+    let has_obb_data = has_archive(DataKind::Obb);
+    let has_external_data = has_archive(DataKind::External);
+    let has_media_data = has_archive(DataKind::Media);
+
+    // data-only backups have no APK to read the version from
+    let apk_info = match has_apk {
+        true => apk_infos.get_or_read(package_name, Path::new(&app_dir)),
+        false => None,
+    };
+    let manifest = apk_info.as_ref().map(|info| &info.manifest);
+
+    let datetime = config.timezone.to_naive(backup_time);
+    let properties_datetime = datetime.format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
+
+    let properties = NeoBackupProperties {
+        backup_version_code: config.backup_version_code,
+        package_name: package_name.clone(),
+        package_label: label_resolver
+            .resolve(package_name)
+            .unwrap_or_else(|| package_name.clone()),
+        version_name: manifest
+            .and_then(|manifest| manifest.version_name.clone())
+            .unwrap_or_else(|| "0.0.0".to_string()),
+        version_code: manifest
+            .and_then(|manifest| manifest.version_code)
+            .unwrap_or(0),
+        backup_date: properties_datetime.clone(),
+        is_system: !config.installed_packages.contains(package_name),
+        has_apk,
+        has_app_data,
+        has_devices_protected_data,
+        has_obb_data,
+        has_external_data,
+        has_media_data,
+        compression_type: config.compression.extension().to_string(),
+        cpu_arch: apk_info
+            .as_ref()
+            .map(|info| info.cpu_arch.clone())
+            .unwrap_or_else(|| config.default_cpu_arch.clone()),
+        size: 0,
+    };
+
+    let filename_datetime = datetime.format("%Y-%m-%d-%H-%M-%S-%3f").to_string();
+    let filename = format!("{}-user_{}", filename_datetime, user_id);
+
+    NeoBackupPropertiesFile {
+        name: filename,
+        content: properties,
+        min_sdk_version: manifest.and_then(|manifest| manifest.min_sdk_version),
+        target_sdk_version: manifest.and_then(|manifest| manifest.target_sdk_version),
+    }
+}
+
+fn find_tar_files(tar_dir: &Path) -> Vec<walkdir::DirEntry> {
+    WalkDir::new(tar_dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry.path().is_file()
+                && entry.path().extension().and_then(|ext| ext.to_str()) == Some("tar")
+        })
+        .collect()
+}
+
+// a tar holding nothing but the package's own directory; symlinks, empty directories and special
+// files are app data too
+fn is_tar_empty(tar_path: &Path, config: &Config) -> Result<bool, io::Error> {
+    let mut archive = open_archive(tar_path, config)?;
+
+    Ok(archive
+        .entries()?
+        .filter_map(|entry| entry.ok())
+        .all(|entry| {
+            entry
+                .path()
+                .map(|path| path.components().all(|part| part == Component::CurDir))
+                .unwrap_or(false)
+        }))
+}
+
+// an archive being compressed as it's written
+enum CompressedWriter {
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl CompressedWriter {
+    fn create(path: &Path, config: &Config) -> Result<Self, io::Error> {
+        let file = BufWriter::with_capacity(config.read_buffer_size, File::create(path)?);
+        match config.compression {
+            Compression::Gzip => {
+                let level = config
+                    .compression_level
+                    .map_or_else(flate2::Compression::default, flate2::Compression::new);
+                Ok(CompressedWriter::Gzip(GzEncoder::new(file, level)))
+            }
+            // zstd treats 0 as its default level
+            Compression::Zstd => Ok(CompressedWriter::Zstd(zstd::Encoder::new(
+                file,
+                config.compression_level.unwrap_or(0) as i32,
+            )?)),
+        }
+    }
+
+    fn finish(self) -> Result<(), io::Error> {
+        let mut file = match self {
+            CompressedWriter::Gzip(encoder) => encoder.finish()?,
+            CompressedWriter::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Gzip(encoder) => encoder.write(buf),
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(encoder) => encoder.flush(),
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// merges the per-chunk tars into one archive, compressing the entries as they are appended
+fn merge_tar_files(
+    tar_files: Vec<PathBuf>,
+    output_path: &Path,
+    config: &Config,
+) -> Result<(), io::Error> {
+    // taken here rather than per package: a worker waiting for the kinds merged in parallel may
+    // pick up another package, and block on a slot while holding one
+    let _slot = config.archive_slots.acquire();
+
+    // written under a temporary name and renamed once complete, so a crash halfway leaves no
+    // truncated archive that looks finished
+    let mut temp_file_name = output_path.file_name().unwrap_or_default().to_os_string();
+    temp_file_name.push(".tmp");
+    let temp_path = output_path.with_file_name(temp_file_name);
+    let mut output_tar = tar::Builder::new(CompressedWriter::create(&temp_path, config)?);
+
+    let mut entry_count = 0;
+    for tar_path in &tar_files {
+        let mut archive = open_archive(tar_path, config)?;
+        archive
+            .entries()?
+            .filter_map(|entry| entry.ok())
+            .try_for_each(|mut entry| -> Result<(), io::Error> {
+                let xattrs = read_xattrs(&mut entry);
+                append_pax_header(&xattrs, entry.size(), &mut output_tar, config)?;
+                let mut header = entry.header().clone();
+                let path = entry.path()?.to_path_buf();
+                match (header.entry_type(), entry.link_name()?) {
+                    // the header only has room for 100 bytes of target, append_link adds a GNU
+                    // long link record for longer ones like an app's lib -> /data/app/~~.../lib/arm64
+                    (EntryType::Symlink | EntryType::Link, Some(link_name)) => {
+                        let link_name = link_name.to_path_buf();
+                        output_tar.append_link(&mut header, path, link_name)?;
+                    }
+                    // streamed, a file of several GiB doesn't fit in memory
+                    _ => output_tar.append_data(&mut header, path, &mut entry)?,
+                }
+                entry_count += 1;
+                Ok(())
+            })?;
+    }
+
+    output_tar.into_inner()?.finish()?;
+    if config.verify_output {
+        verify_archive(&temp_path, entry_count, config).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} doesn't read back: {}", output_path.display(), e),
+            )
+        })?;
+    }
+    fs::rename(&temp_path, output_path)?;
+    for tar_path in tar_files {
+        fs::remove_file(tar_path)?;
+    }
+    Ok(())
+}
+
+// decompresses a written archive and walks all of its entries, so a corrupt one shows up now
+// rather than when Neo Backup fails to restore it
+fn verify_archive(
+    archive_path: &Path,
+    expected_entry_count: usize,
+    config: &Config,
+) -> Result<(), io::Error> {
+    let file = BufReader::with_capacity(config.read_buffer_size, File::open(archive_path)?);
+    let reader: Box<dyn Read> = match config.compression {
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+    };
+    let mut archive = Archive::new(reader);
+
+    let mut entry_count = 0;
+    for entry in archive.entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+        entry_count += 1;
+    }
+    match entry_count == expected_entry_count {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} entries instead of the {} written",
+                entry_count, expected_entry_count
+            ),
+        )),
+    }
+}
+
+fn compress_migrated_tar_files(user_id: i32, config: &Config) -> Result<(), io::Error> {
+    let user_dir = format!("{}/{}", config.output_dir, user_id);
+    let user_path = Path::new(&user_dir);
+
+    fs::read_dir(user_path)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .par_bridge()
+        .try_for_each(|entry| {
+            check_interrupted()?;
+            let app_dir = entry.path();
+
+            let tar_files: Vec<_> = find_tar_files(&app_dir);
+
+            // delete empty tar files
+            tar_files
+                .iter()
+                .filter(|tar_file| is_tar_empty(tar_file.path(), config).unwrap_or(false))
+                .try_for_each(|tar_file| -> Result<(), io::Error> {
+                    record_warning(format!(
+                        "Dropped empty archive {}",
+                        tar_file.path().display()
+                    ));
+                    fs::remove_file(tar_file.path())?;
+                    Ok(())
+                })?;
+
+            let tar_files: Vec<_> = find_tar_files(&app_dir);
+
+            // merge the per-chunk tars of each kind (e.g. 000-data.ext4.win000-data.tar) straight
+            // into its compressed archive, e.g. data.tar.zst
+            DataKind::ALL.par_iter().try_for_each(|kind| {
+                let chunk_tar_files: Vec<_> = tar_files
+                    .iter()
+                    .filter(|entry| {
+                        entry
+                            .path()
+                            .to_str()
+                            .unwrap()
+                            .ends_with(&format!("-{}", kind.archive_name()))
+                    })
+                    .map(|entry| entry.path().to_path_buf())
+                    .collect();
+
+                match chunk_tar_files.is_empty() {
+                    true => Ok(()),
+                    false => {
+                        let output_path = app_dir
+                            .join(kind.compressed_archive_name(config.compression.extension()));
+                        merge_tar_files(chunk_tar_files, &output_path, config)
+                    }
+                }
+            })
+        })
+}
+
+fn assemble_neo_backup_file_structure(
+    user_id: UserId,
+    package_name: &PackageName,
+    properties_file: NeoBackupPropertiesFile,
+    config: &Config,
+) -> Result<Option<PackageReport>, EvacuateError> {
+    let package_error = |source| EvacuateError::Package {
+        user_id,
+        package_name: package_name.clone(),
+        source,
+    };
+    let app_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+    let NeoBackupPropertiesFile {
+        name: filename,
+        content: mut properties,
+        min_sdk_version,
+        target_sdk_version,
+    } = properties_file;
+
+    if !properties.has_apk
+        && !properties.has_app_data
+        && !properties.has_devices_protected_data
+        && !properties.has_obb_data
+        && !properties.has_external_data
+        && !properties.has_media_data
+    {
+        record_warning(format!(
+            "Skipped {} of user {}, neither APK nor app data was found",
+            package_name, user_id
+        ));
+        // nothing in it would restore, e.g. only archives dropped as empty
+        if Path::new(&app_dir).exists() {
+            fs::remove_dir_all(&app_dir).map_err(package_error)?;
+        }
+        return Ok(None);
+    }
+
+    let new_dir = format!("{}/{}", &app_dir, filename);
+    fs::create_dir_all(&new_dir).map_err(package_error)?;
+
+    for entry in fs::read_dir(&app_dir).map_err(package_error)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_file() {
+            let dest_path = format!("{}/{}", &new_dir, entry.file_name().to_string_lossy());
+            fs::rename(&path, &dest_path).map_err(package_error)?;
+        }
+    }
+
+    // summed once the archives are compressed and moved, so it matches what ends up on disk
+    properties.size = backup_dir_size(Path::new(&new_dir)).map_err(package_error)?;
+
+    // written under a temporary name and renamed once complete, a .properties file marks a
+    // finished backup for --resume
+    let properties_file_path = format!("{}/{}.properties", &app_dir, filename);
+    let temp_properties_file_path = format!("{}.tmp", properties_file_path);
+    let properties_file = File::create(&temp_properties_file_path).map_err(package_error)?;
+    // Neo Backup parses the file as plain JSON, so minified output restores the same
+    match config.compact_json {
+        true => serde_json::to_writer(properties_file, &properties)
+            .map_err(|e| package_error(e.into()))?,
+        false => serde_json::to_writer_pretty(properties_file, &properties)
+            .map_err(|e| package_error(e.into()))?,
+    }
+    fs::rename(&temp_properties_file_path, &properties_file_path).map_err(package_error)?;
+
+    Ok(Some(PackageReport {
+        backup_dir: new_dir,
+        properties,
+        min_sdk_version,
+        target_sdk_version,
+    }))
+}
+
+// the .properties file of a backup finished by an earlier run, it's written last so packages
+// without one were interrupted
+fn find_completed_backup(
+    user_id: UserId,
+    package_name: &PackageName,
+    config: &Config,
+) -> Option<PathBuf> {
+    let app_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+    fs::read_dir(app_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.extension().and_then(|ext| ext.to_str()) == Some("properties")
+                && path.with_extension("").is_dir()
+        })
+}
+
+// removes what an interrupted run left of unfinished packages, so they are migrated from scratch
+fn remove_incomplete_backups(user_id: UserId, config: &Config) -> Result<(), io::Error> {
+    let user_dir = format!("{}/{}", config.output_dir, user_id);
+    if !Path::new(&user_dir).is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(&user_dir)? {
+        let entry = entry?;
+        let package_name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() && find_completed_backup(user_id, &package_name, config).is_none()
+        {
+            info!(
+                "Removing the unfinished backup of {} for user {}",
+                package_name, user_id
+            );
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+// the report entry of a backup finished by an earlier run
+fn read_completed_backup(properties_path: &Path) -> Result<PackageReport, io::Error> {
+    let properties: NeoBackupProperties =
+        serde_json::from_reader(BufReader::new(File::open(properties_path)?))?;
+    let backup_dir = properties_path.with_extension("");
+    let manifest = read_apk_manifest(&backup_dir.join("base.apk")).ok();
+    Ok(PackageReport {
+        backup_dir: backup_dir.to_string_lossy().to_string(),
+        properties,
+        min_sdk_version: manifest
+            .as_ref()
+            .and_then(|manifest| manifest.min_sdk_version),
+        target_sdk_version: manifest.and_then(|manifest| manifest.target_sdk_version),
+    })
+}
+
+// total size of the APKs and archives in a backup's dated folder, as shown by Neo Backup
+fn backup_dir_size(backup_dir: &Path) -> Result<i64, io::Error> {
+    let mut size = 0;
+    for entry in fs::read_dir(backup_dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len() as i64;
+        }
+    }
+    Ok(size)
+}
+
+// checks that every assembled backup has a dated folder next to its .properties file and that the
+// has_* flags match the archives in it, returns the problems found
+fn validate_assembled_backups(user_id: UserId, config: &Config) -> Result<Vec<String>, io::Error> {
+    let mut problems = Vec::new();
+
+    for package_name in find_all_extracted_apps(user_id, config)? {
+        let app_dir = PathBuf::from(format!(
+            "{}/{}/{}",
+            config.output_dir, user_id, package_name
+        ));
+        let properties_paths: Vec<PathBuf> = fs::read_dir(&app_dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("properties"))
+            .collect();
+
+        if properties_paths.is_empty() {
+            problems.push(format!("{}: no .properties file", app_dir.display()));
+        }
+
+        for properties_path in properties_paths {
+            let backup_dir = properties_path.with_extension("");
+            if !backup_dir.is_dir() {
+                problems.push(format!(
+                    "{}: backup folder is missing",
+                    properties_path.display()
+                ));
+                continue;
+            }
+
+            let properties: NeoBackupProperties =
+                match serde_json::from_reader(File::open(&properties_path)?) {
+                    Ok(properties) => properties,
+                    Err(e) => {
+                        problems.push(format!("{}: {}", properties_path.display(), e));
+                        continue;
+                    }
+                };
+
+            let archive =
+                |kind: DataKind| kind.compressed_archive_name(&properties.compression_type);
+            [
+                ("hasApk", properties.has_apk, "base.apk".to_string()),
+                (
+                    "hasAppData",
+                    properties.has_app_data,
+                    archive(DataKind::App),
+                ),
+                (
+                    "hasDevicesProtectedData",
+                    properties.has_devices_protected_data,
+                    archive(DataKind::DeviceProtected),
+                ),
+                (
+                    "hasObbData",
+                    properties.has_obb_data,
+                    archive(DataKind::Obb),
+                ),
+                (
+                    "hasExternalData",
+                    properties.has_external_data,
+                    archive(DataKind::External),
+                ),
+                (
+                    "hasMediaData",
+                    properties.has_media_data,
+                    archive(DataKind::Media),
+                ),
+            ]
+            .into_iter()
+            .filter(|(_, flag, file_name)| *flag != backup_dir.join(file_name).exists())
+            .for_each(|(flag_name, flag, file_name)| {
+                problems.push(format!(
+                    "{}: {} is {} but {} is {}",
+                    properties_path.display(),
+                    flag_name,
+                    flag,
+                    file_name,
+                    match flag {
+                        true => "missing",
+                        false => "present",
+                    }
+                ));
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+fn extract_all_apks(
+    tar_indexes: &[TarIndex],
+    bar_apk: &ProgressBar,
+    config: &Config,
+) -> Result<(), EvacuateError> {
+    let apk_fs_items = find_all_apks(tar_indexes, config);
+    bar_apk.set_length(apk_fs_items.len() as u64);
+    bar_apk.set_message(format!("Found {} APK(s)", apk_fs_items.len()));
+
+    for apk_fs_item in apk_fs_items {
+        check_interrupted()?;
+        bar_apk.set_message(format!("Extracting APK: {}", apk_fs_item.package_name()));
+        info!("Extracting the APKs in {}", apk_fs_item.instance_dir_name);
+        let apk_paths =
+            extract_apks_to_temp(tar_indexes, &apk_fs_item, config).map_err(|source| {
+                EvacuateError::Io {
+                    path: PathBuf::from(apk_fs_item.dir_path()),
+                    source,
+                }
+            })?;
+        if config.verify_apk {
+            for apk_path in apk_paths {
+                if let Err(e) = verify_apk(&apk_path) {
+                    record_warning(format!(
+                        "APK failed verification, re-download the app instead: {}/{}: {}",
+                        apk_fs_item.instance_dir_name,
+                        apk_path.file_name().unwrap_or_default().to_string_lossy(),
+                        e
+                    ));
+                }
+            }
+        }
+        bar_apk.inc(1);
+    }
+
+    Ok(())
+}
+
+// lays the extracted APKs out as apks/<package>/ (base + splits) for --apk-only
+fn collect_apks_only(config: &Config) -> Result<Vec<(PackageName, String, usize)>, io::Error> {
+    let apk_temp_dir = PathBuf::from(format!("{}/{}", config.output_dir, APK_TEMP_DIR));
+    if !apk_temp_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut package_names: Vec<PackageName> = fs::read_dir(&apk_temp_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    package_names.sort();
+
+    let mut collected = Vec::new();
+    for package_name in package_names {
+        let Some(instance_dir) = choose_apk_instance_dir(&apk_temp_dir.join(&package_name))? else {
+            continue;
+        };
+        let dest_dir = format!("{}/{}/{}", config.output_dir, APKS_DIR, package_name);
+        fs::create_dir_all(&dest_dir)?;
+
+        let mut apk_count = 0;
+        for entry in fs::read_dir(&instance_dir)?.filter_map(Result::ok) {
+            let dest_path = format!("{}/{}", dest_dir, entry.file_name().to_string_lossy());
+            fs::rename(entry.path(), dest_path)?;
+            apk_count += 1;
+        }
+
+        let instance_dir_name = instance_dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        collected.push((package_name, instance_dir_name, apk_count));
+    }
+
+    if let Some(csv_path) = &config.apk_csv {
+        let mut csv = String::from("package,version_name,version_code,install_dir,apk_count\n");
+        for (package_name, instance_dir_name, apk_count) in &collected {
+            // the version columns stay empty when the manifest can't be read
+            let base_apk_path = Path::new(&config.output_dir)
+                .join(APKS_DIR)
+                .join(package_name)
+                .join("base.apk");
+            let manifest = read_apk_manifest(&base_apk_path).unwrap_or_default();
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                package_name,
+                csv_field(manifest.version_name.as_deref().unwrap_or_default()),
+                manifest
+                    .version_code
+                    .map_or(String::new(), |version_code| version_code.to_string()),
+                instance_dir_name,
+                apk_count
+            ));
+        }
+        fs::write(csv_path, csv)?;
+    }
+
+    Ok(collected)
+}
+
+// removes the intermediate tars and APKs, returns the directories that were removed; failing
+// to remove one only warrants a warning at the end of a long run
+fn cleanup_temp_dir(config: &Config) -> Vec<String> {
+    [DECOMPRESSED_TAR_DIR, APK_TEMP_DIR]
+        .iter()
+        .map(|temp_dir| format!("{}/{}", config.output_dir, temp_dir))
+        .filter(|temp_dir| Path::new(temp_dir).exists())
+        .filter(|temp_dir| match fs::remove_dir_all(temp_dir) {
+            Ok(()) => true,
+            Err(e) => {
+                record_warning(format!("Couldn't remove {}: {}", temp_dir, e));
+                false
+            }
+        })
+        .collect()
+}
+
+fn find_adb_devices() -> Result<Vec<String>, io::Error> {
+    let output = Command::new("adb")
+        .arg("devices")
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                "adb not found on PATH, install Android SDK Platform-Tools first",
+            ),
+            _ => e,
+        })?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "adb devices failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // "List of devices attached" is followed by "<serial>\t<state>" lines
+    let devices = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, state)| state.trim() == "device")
+        .map(|(serial, _)| serial.trim().to_string())
+        .collect();
+
+    Ok(devices)
+}
+
+fn choose_adb_device(devices: Vec<String>) -> Result<String, io::Error> {
+    match devices.len() {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No device found by adb, check the cable and that USB debugging is authorized",
+        )),
+        1 => Ok(devices[0].clone()),
+        _ => {
+            println!("Multiple devices connected:");
+            for serial in &devices {
+                println!("  {}", serial);
+            }
+            print!("Enter the serial of the device to push to: ");
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            io::stdin().lock().read_line(&mut answer)?;
+            let answer = answer.trim();
+            devices
+                .into_iter()
+                .find(|serial| serial == answer)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown device serial: {}", answer),
+                    )
+                })
+        }
+    }
+}
+
+// asks before copying what may be gigabytes to the device; without a terminal to ask on, the
+// answer is the --push-to-device it was started with
+fn confirm_push(
+    package_count: usize,
+    size: u64,
+    serial: &str,
+    device_dir: &str,
+) -> Result<bool, io::Error> {
+    if !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    print!(
+        "Push {} package(s) ({}) to {} on {}? [y/N] ",
+        package_count,
+        HumanBytes(size),
+        device_dir,
+        serial
+    );
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// returns false when the push was called off
+fn push_to_device(user_id: UserId, device_dir: &str, config: &Config) -> Result<bool, io::Error> {
+    let serial = choose_adb_device(find_adb_devices()?)?;
+    let device_dir = device_dir.trim_end_matches('/');
+
+    let package_names = find_all_extracted_apps(user_id, config)?;
+    let size = WalkDir::new(format!("{}/{}", config.output_dir, user_id))
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum();
+    if !confirm_push(package_names.len(), size, &serial, device_dir)? {
+        return Ok(false);
+    }
+
+    for package_name in package_names {
+        let app_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+        println!("Pushing {} to {}", package_name, serial);
+
+        let output = Command::new("adb")
+            .args(["-s", &serial, "push", &app_dir, &format!("{}/", device_dir)])
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "adb push of {} failed: {}",
+                package_name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+    }
+
+    Ok(true)
+}
+
+// packs a user's migrated backups into <user>.zip next to their folder, to copy one file to the
+// device instead of thousands. Neo Backup can't read zips, it's unpacked into its folder there
+fn zip_user_backups(
+    user_id: UserId,
+    bar_zip: &ProgressBar,
+    config: &Config,
+) -> Result<PathBuf, io::Error> {
+    let user_dir = PathBuf::from(format!("{}/{}", config.output_dir, user_id));
+    let zip_path = PathBuf::from(format!("{}/{}.zip", config.output_dir, user_id));
+    let temp_zip_path = PathBuf::from(format!("{}.tmp", zip_path.display()));
+
+    let entries: Vec<walkdir::DirEntry> = WalkDir::new(&user_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    for entry in &entries {
+        bar_zip.inc_length(entry.metadata()?.len());
+    }
+
+    // the archives and APKs are compressed already
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(&temp_zip_path)?));
+    for entry in entries {
+        check_interrupted()?;
+        let name = entry
+            .path()
+            .strip_prefix(&user_dir)
+            .map_err(io::Error::other)?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        match entry.file_type().is_dir() {
+            true => zip.add_directory(name, options).map_err(io::Error::other)?,
+            false => {
+                let size = entry.metadata()?.len();
+                zip.start_file(name, options.large_file(size >= u32::MAX as u64))
+                    .map_err(io::Error::other)?;
+                io::copy(&mut File::open(entry.path())?, &mut zip)?;
+                bar_zip.inc(size);
+            }
+        }
+    }
+    zip.finish().map_err(io::Error::other)?.flush()?;
+
+    fs::rename(&temp_zip_path, &zip_path)?;
+    Ok(zip_path)
+}
+
+// lists the packages each user's migrated backups would hold and what's in them
+fn print_migration_plan(
+    tar_indexes: &[TarIndex],
+    user_ids: &[UserId],
+    config: &Config,
+    data_kinds: &[DataKind],
+) {
+    let apk_packages: HashSet<PackageName> = match config.data_only {
+        true => HashSet::new(),
+        false => find_all_apks(tar_indexes, config)
+            .into_iter()
+            .map(|apk| apk.package_name().to_string())
+            .collect(),
+    };
+
+    for &user_id in user_ids {
+        let packages_by_kind: Vec<(DataKind, HashSet<PackageName>)> = data_kinds
+            .iter()
+            .map(|&kind| {
+                let packages = tar_indexes
+                    .iter()
+                    .flat_map(|tar_index| find_all_app_data(tar_index, user_id, kind, config))
+                    .collect();
+                (kind, packages)
+            })
+            .collect();
+
+        let apk_backup_packages = match config.apk_backups {
+            true => find_apk_backup_packages(tar_indexes, user_id, config),
+            false => HashSet::new(),
+        };
+
+        let mut package_names: Vec<&PackageName> = packages_by_kind
+            .iter()
+            .flat_map(|(_, packages)| packages)
+            .chain(&apk_backup_packages)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        package_names.sort();
+
+        println!();
+        println!("User {}: {} package(s)", user_id, package_names.len());
+        for package_name in package_names {
+            let contents: Vec<&str> = apk_packages
+                .contains(package_name)
+                .then_some("APK")
+                .into_iter()
+                .chain(
+                    packages_by_kind
+                        .iter()
+                        .filter(|(_, packages)| packages.contains(package_name))
+                        .map(|(kind, _)| kind.description()),
+                )
+                .collect();
+            println!("  {}: {}", package_name, contents.join(", "));
+        }
+    }
+}
+
+// the raw contents of the backup: each user's packages with data, and where their APK is
+// installed; "-" for packages without one, i.e. system apps
+fn print_backup_contents(tar_indexes: &[TarIndex], users: &[UserId], config: &Config) {
+    let mut apk_dirs: HashMap<PackageName, Vec<String>> = HashMap::new();
+    for apk in find_all_apks(tar_indexes, config) {
+        apk_dirs
+            .entry(apk.package_name().to_string())
+            .or_default()
+            .push(apk.dir_path());
+    }
+
+    let mut user_ids: Vec<UserId> = tar_indexes
+        .iter()
+        .flat_map(find_all_users)
+        .filter(|user_id| users.is_empty() || users.contains(user_id))
+        .collect();
+    user_ids.sort();
+    user_ids.dedup();
+
+    for user_id in user_ids {
+        let mut package_names: Vec<PackageName> = tar_indexes
+            .iter()
+            .flat_map(|tar_index| find_all_app_data(tar_index, user_id, DataKind::App, config))
+            .collect();
+        package_names.sort();
+        package_names.dedup();
+
+        println!();
+        println!("User {}: {} package(s)", user_id, package_names.len());
+        for package_name in package_names {
+            let install_dirs = apk_dirs
+                .get(&package_name)
+                .map(|dirs| dirs.join(", "))
+                .unwrap_or_else(|| "-".to_string());
+            println!("  {}  {}", package_name, install_dirs);
+        }
+    }
+}
+
+pub fn write_report(
+    report: &MigrationReport,
+    report_path: &Path,
+    format: ReportFormat,
+) -> Result<(), io::Error> {
+    let mut writer = io::BufWriter::new(File::create(report_path)?);
+    match format {
+        ReportFormat::Json => serde_json::to_writer_pretty(&mut writer, report)?,
+        ReportFormat::Ndjson => {
+            for user in &report.users {
+                for package in &user.packages {
+                    let line = ReportLine::Package {
+                        user_id: user.user_id,
+                        package,
+                    };
+                    serde_json::to_writer(&mut writer, &line)?;
+                    writeln!(writer)?;
+                }
+            }
+            for warning in &report.warnings {
+                serde_json::to_writer(&mut writer, &ReportLine::Warning { message: warning })?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+// quoted when it would break the row, with inner quotes doubled
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+// one row per migrated package, for a spreadsheet
+pub fn write_manifest(report: &MigrationReport, manifest_path: &Path) -> Result<(), io::Error> {
+    let mut writer = io::BufWriter::new(File::create(manifest_path)?);
+    writeln!(
+        writer,
+        "user_id,package_name,label,version_name,version_code,has_apk,has_data,has_de_data,size,\
+         min_sdk_version,target_sdk_version"
+    )?;
+    for user in &report.users {
+        for package in &user.packages {
+            let properties = &package.properties;
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                user.user_id,
+                csv_field(&properties.package_name),
+                csv_field(&properties.package_label),
+                csv_field(&properties.version_name),
+                properties.version_code,
+                properties.has_apk,
+                properties.has_app_data,
+                properties.has_devices_protected_data,
+                properties.size,
+                package
+                    .min_sdk_version
+                    .map(|version| version.to_string())
+                    .unwrap_or_default(),
+                package
+                    .target_sdk_version
+                    .map(|version| version.to_string())
+                    .unwrap_or_default()
+            )?;
+        }
+    }
+    writer.flush()
+}
+
+pub const RESTORE_INSTRUCTIONS_FILE: &str = "RESTORE_INSTRUCTIONS.txt";
+
+// the closing instructions, tailored to what the run produced, for later when the terminal is
+// long gone
+pub fn write_restore_instructions(
+    report: &MigrationReport,
+    output_dir: &str,
+    device_dir: &str,
+) -> Result<PathBuf, io::Error> {
+    let path = Path::new(output_dir).join(RESTORE_INSTRUCTIONS_FILE);
+    let mut writer = io::BufWriter::new(File::create(&path)?);
+
+    let users: Vec<&UserReport> = report
+        .users
+        .iter()
+        .filter(|user| !user.packages.is_empty())
+        .collect();
+    let Some(primary_user) = users.first() else {
+        writeln!(
+            writer,
+            "No app data was found in the backup, nothing was migrated."
+        )?;
+        writer.flush()?;
+        return Ok(path);
+    };
+
+    writeln!(writer, "Migrated backups:")?;
+    for (position, user) in users.iter().enumerate() {
+        writeln!(
+            writer,
+            "  {}/ - user {}{}: {} app(s)",
+            user.user_id,
+            user.user_id,
+            match position {
+                0 => "",
+                _ => " (another user or a work profile)",
+            },
+            user.packages.len()
+        )?;
+    }
+    writeln!(writer)?;
+    writeln!(writer, "To restore them with Neo Backup:")?;
+    writeln!(
+        writer,
+        "  1. Copy the contents of {}/ to Neo Backup's backup folder on your device (e.g. {}), \
+         so each app's folder sits directly in it.",
+        primary_user.user_id, device_dir
+    )?;
+    writeln!(
+        writer,
+        "  2. Open Neo Backup and refresh the list of backups (or pick the backup folder again in \
+         its settings) until the migrated apps show up."
+    )?;
+    writeln!(
+        writer,
+        "  3. Restore the apps you need one at a time, APK and data together."
+    )?;
+    if users.len() > 1 {
+        writeln!(
+            writer,
+            "  The other users' backups are restored the same way, running Neo Backup in that user \
+             or profile."
+        )?;
+    }
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "WARNING: Do not restore all backups at once! The migrated backups may contain system \
+         apps and data that are not compatible with your device. Restore only the apps you need."
+    )?;
+    if !report.warnings.is_empty() {
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{} warning(s) came up during the migration:",
+            report.warnings.len()
+        )?;
+        for warning in &report.warnings {
+            writeln!(writer, "  - {}", warning)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(path)
+}
+
+// creates the output dir and makes sure files can be written to it, so read-only media or a
+// mistyped path fail right away instead of after decompressing the backup
+fn check_output_dir(output_dir: &str) -> Result<(), io::Error> {
+    let probe_path = Path::new(output_dir).join(".twrp_evacuate_write_test");
+    fs::create_dir_all(output_dir)
+        .and_then(|_| File::create(&probe_path))
+        .and_then(|_| fs::remove_file(&probe_path))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Output directory {} is not writable: {}", output_dir, e),
+            )
+        })
+}
+
+// what an earlier run left in the output directory that a new one would get mixed into: the user
+// directories (only those of `users` when given), or apks/ with --apk-only
+pub fn find_previous_output(output_dir: &str, users: &[UserId], apk_only: bool) -> Vec<PathBuf> {
+    if apk_only {
+        let apks_dir = Path::new(output_dir).join(APKS_DIR);
+        return match apks_dir.is_dir() {
+            true => vec![apks_dir],
+            false => Vec::new(),
+        };
+    }
+
+    let Ok(entries) = fs::read_dir(output_dir) else {
+        return Vec::new();
+    };
+    let mut user_dirs: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<UserId>().ok())
+                .is_some_and(|user_id| users.is_empty() || users.contains(&user_id))
+        })
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    user_dirs.sort();
+    user_dirs
+}
+
+// asks whether the output of an earlier run may be deleted; without a terminal to ask on, the
+// answer is no
+pub fn confirm_replace_output(previous_output: &[PathBuf]) -> Result<bool, io::Error> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    println!("The output directory holds the output of an earlier run:");
+    for path in previous_output {
+        println!("  {}", path.display());
+    }
+    print!("Delete it and migrate again? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// free space on the filesystem holding `path`, None when it can't be told
+fn available_space(path: &Path) -> Option<u64> {
+    let path = fs::canonicalize(path).ok()?;
+    Disks::new_with_refreshed_list()
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+// TWRP's gzip typically shrinks app data to about half, so this much space is needed to
+// decompress a backup; a guess, but enough to catch "5 GB free, 40 GB needed"
+const DECOMPRESSION_RATIO_GUESS: u64 = 2;
+
+// required before decompressing: the backup times DECOMPRESSION_RATIO_GUESS. Required before
+// extracting: the selected data uncompressed, which sits in the per-chunk tars until finalize()
+// compresses them one package at a time, whatever the compression ratio
+fn check_free_space(estimated_size: u64, config: &Config) -> Result<(), io::Error> {
+    let Some(available) = available_space(Path::new(&config.output_dir)) else {
+        return Ok(());
+    };
+    debug!(
+        "About {} needed in {}, {} available",
+        HumanBytes(estimated_size),
+        config.output_dir,
+        HumanBytes(available)
+    );
+    if available >= estimated_size {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::StorageFull,
+        format!(
+            "Not enough free space in {}: the migration needs about {}, only {} is available. \
+             Free some space or pick another --output-dir, or use --skip-space-check to try anyway",
+            config.output_dir,
+            HumanBytes(estimated_size),
+            HumanBytes(available)
+        ),
+    ))
+}
+
+// what to migrate and how; the command line options map onto these
+pub struct MigrationOptions {
+    // a chunk of the backup (data.ext4.win000, ...), the other chunks are found next to it
+    pub input: String,
+    // the input is the decompressed backup as a plain tar, instead of a chunk
+    pub from_tar: bool,
+    pub output_dir: String,
+    pub read_buffer_kib: usize,
+    pub max_open_archives: usize,
+    // how many packages read the decompressed tars at once
+    pub tar_readers: usize,
+    pub verify_checksums: bool,
+    // index the backup in memory, without writing anything
+    pub dry_run: bool,
+    pub keep_temp: bool,
+    // refuse to decompress and extract when the output filesystem clearly lacks the space for it
+    pub check_free_space: bool,
+    // skip packages an earlier, interrupted run into the same output directory finished
+    pub resume: bool,
+    // leave the APKs out, the backups only hold the app data
+    pub data_only: bool,
+    // leave the app data out, the backups only hold the APKs
+    pub apk_backups: bool,
+    pub de_data: bool,
+    pub obb: bool,
+    pub external_data: bool,
+    pub media_data: bool,
+    // extra device protected data locations, "{user}" stands for the user id
+    pub de_paths: Vec<String>,
+    pub exclude_patterns: Vec<Pattern>,
+    pub include_cache: bool,
+    // keep the files' SELinux labels from the backup
+    pub selinux: bool,
+    // replace the app ids in file owners with placeholders, see normalized_owner_id
+    pub normalize_owner: bool,
+    pub compression: Compression,
+    // None uses the codec's own default level
+    pub compression_level: Option<u32>,
+    pub packages: Vec<PackageName>,
+    pub excluded_packages: Vec<Pattern>,
+    pub users: Vec<UserId>,
+    pub skip_system: bool,
+    pub compact_json: bool,
+    pub verify_apk: bool,
+    // "package,label" CSV files, earlier ones take priority
+    pub labels: Vec<PathBuf>,
+    pub apk_csv: Option<PathBuf>,
+    pub validate_after_assemble: bool,
+    // read every written archive back before it gets its final name
+    pub verify_output: bool,
+    pub default_cpu_arch: String,
+    // backupVersionCode written to .properties, see NEO_BACKUP_VERSIONS
+    pub backup_version_code: i32,
+    // overrides the time the backup was made, read from the TWRP folder name otherwise
+    pub backup_date: Option<NaiveDateTime>,
+    pub timezone: Timezone,
+}
+
+impl MigrationOptions {
+    pub fn new(input: impl Into<String>) -> Self {
+        MigrationOptions {
+            input: input.into(),
+            from_tar: false,
+            output_dir: DEFAULT_OUTPUT_DIR.to_string(),
+            read_buffer_kib: DEFAULT_READ_BUFFER_KIB,
+            max_open_archives: DEFAULT_MAX_OPEN_ARCHIVES,
+            tar_readers: available_threads(),
+            verify_checksums: true,
+            dry_run: false,
+            keep_temp: false,
+            check_free_space: true,
+            resume: false,
+            data_only: false,
+            apk_backups: false,
+            de_data: true,
+            obb: true,
+            external_data: false,
+            media_data: false,
+            de_paths: Vec::new(),
+            exclude_patterns: Vec::new(),
+            include_cache: false,
+            selinux: true,
+            normalize_owner: false,
+            compression: Compression::Zstd,
+            compression_level: None,
+            packages: Vec::new(),
+            excluded_packages: Vec::new(),
+            users: Vec::new(),
+            skip_system: false,
+            compact_json: false,
+            verify_apk: false,
+            labels: Vec::new(),
+            apk_csv: None,
+            validate_after_assemble: false,
+            verify_output: false,
+            default_cpu_arch: DEFAULT_CPU_ARCH.to_string(),
+            backup_version_code: 8003,
+            backup_date: None,
+            timezone: Timezone::Local,
+        }
+    }
+}
+
+// what discover() found in the backup, within the selected users and packages
+pub struct BackupSummary {
+    pub user_ids: Vec<UserId>,
+    pub user_app_count: usize,
+    pub system_app_count: usize,
+    pub size: u64,
+    pub skipped_system_packages: Vec<PackageName>,
+}
+
+// runs a migration step by step: index() and discover() read the backup, extract() pulls the
+// APKs and app data out of it and finalize() assembles the Neo Backup folders
+pub struct Migrator {
+    options: MigrationOptions,
+    config: Config,
+    progress: MultiProgress,
+    style: ProgressStyle,
+    bytes_style: ProgressStyle,
+    label_resolver: LabelResolverChain,
+    apk_infos: Arc<ApkInfoCache>,
+    data_kinds: Vec<DataKind>,
+    tar_indexes: Vec<TarIndex>,
+    user_ids: Vec<UserId>,
+    // what discover() estimated the selected APKs and app data to take, uncompressed
+    estimated_size: u64,
+    timings: HashMap<PackageName, Duration>,
+}
+
+// the bars only differ in what they count; the message shows what is being worked on
+fn progress_style(counter: &str) -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(&format!(
+            "{{spinner:.green}} {{bar:20.cyan/blue}} {} {{msg}}",
+            counter
+        ))
+        .unwrap()
+}
+
+impl Migrator {
+    // progress bars are drawn on `progress`, so a caller can route its log output through it
+    pub fn new(options: MigrationOptions, progress: MultiProgress) -> Result<Self, EvacuateError> {
+        if !options.dry_run {
+            check_output_dir(&options.output_dir)?;
+        }
+        let config = Config {
+            output_dir: options.output_dir.clone(),
+            read_buffer_size: options.read_buffer_kib.max(1) * 1024,
+            exclude_patterns: options.exclude_patterns.clone(),
+            include_cache: options.include_cache,
+            selinux: options.selinux,
+            normalize_owner: options.normalize_owner,
+            compression: options.compression,
+            compression_level: options.compression_level,
+            packages: options.packages.iter().cloned().collect(),
+            excluded_packages: options.excluded_packages.clone(),
+            skip_system: options.skip_system,
+            data_only: options.data_only,
+            apk_backups: options.apk_backups,
+            installed_packages: HashSet::new(),
+            compact_json: options.compact_json,
+            verify_output: options.verify_output,
+            verify_apk: options.verify_apk,
+            verify_checksums: options.verify_checksums,
+            shared_storage: fs::canonicalize(&options.output_dir)
+                .map(|path| is_android_shared_storage(&path))
+                .unwrap_or(false),
+            archive_slots: Slots::new(options.max_open_archives),
+            tar_reader_slots: Slots::new(options.tar_readers),
+            apk_csv: options.apk_csv.clone(),
+            default_cpu_arch: options.default_cpu_arch.clone(),
+            backup_version_code: options.backup_version_code,
+            timezone: options.timezone,
+            de_path_templates: DEFAULT_DE_PATH_TEMPLATES
+                .iter()
+                .map(|template| template.to_string())
+                .chain(options.de_paths.iter().cloned())
+                .collect(),
+        };
+        if config.shared_storage {
+            // uid/gid only live in the tar headers, so they are kept even here
+            record_warning(
+                "Output is on Android shared storage, APK file times can't be preserved there"
+                    .to_string(),
+            );
+        }
+
+        let mut label_resolver = LabelResolverChain::default();
+        for csv_path in &options.labels {
+            let csv_resolver =
+                CsvLabelResolver::load(csv_path).map_err(|source| EvacuateError::Io {
+                    path: csv_path.clone(),
+                    source,
+                })?;
+            label_resolver.push(Box::new(csv_resolver));
+        }
+        let apk_infos = Arc::new(ApkInfoCache {
+            output_dir: config.output_dir.clone(),
+            default_cpu_arch: config.default_cpu_arch.clone(),
+            infos: Mutex::new(HashMap::new()),
+        });
+        label_resolver.push(Box::new(ApkLabelResolver {
+            apk_infos: apk_infos.clone(),
+        }));
+
+        let data_kinds = DataKind::ALL
+            .into_iter()
+            .filter(|_| !options.apk_backups)
+            .filter(|kind| match kind {
+                DataKind::App => true,
+                DataKind::DeviceProtected => options.de_data,
+                DataKind::Obb => options.obb,
+                DataKind::External => options.external_data,
+                DataKind::Media => options.media_data,
+            })
+            .collect();
+
+        Ok(Migrator {
+            options,
+            config,
+            progress,
+            style: progress_style("{pos}/{len} ({per_sec}, {eta})"),
+            bytes_style: progress_style("{bytes}/{total_bytes} ({bytes_per_sec}, {eta})"),
+            label_resolver,
+            apk_infos,
+            data_kinds,
+            tar_indexes: Vec::new(),
+            user_ids: Vec::new(),
+            estimated_size: 0,
+            timings: HashMap::new(),
+        })
+    }
+
+    fn add_bar(&self, length: u64) -> ProgressBar {
+        let bar = self.progress.add(ProgressBar::new(length));
+        bar.set_style(self.style.clone());
+        // no ticker thread for bars nobody sees
+        if !self.progress.is_hidden() {
+            bar.enable_steady_tick(time::Duration::from_millis(100));
+        }
+        bar
+    }
+
+    // a bar counting bytes, with throughput and ETA
+    fn add_bytes_bar(&self, length: u64) -> ProgressBar {
+        let bar = self.add_bar(length);
+        bar.set_style(self.bytes_style.clone());
+        bar
+    }
+
+    // checks the backup chunks against their digests, then decompresses and indexes them
+    pub fn index(&mut self) -> Result<(), EvacuateError> {
+        let config = &self.config;
+
+        // a plain tar is indexed as it is, there is nothing to verify or decompress
+        if self.options.from_tar {
+            let tar_path = PathBuf::from(&self.options.input);
+            check_plain_tar(&tar_path).map_err(|source| EvacuateError::TarRead {
+                path: tar_path.clone(),
+                source,
+            })?;
+            check_data_partition(&tar_path, config)?;
+
+            let bar_index = self.add_bytes_bar(0);
+            bar_index.set_message("Indexing the tar");
+            self.tar_indexes = vec![build_tar_index(&tar_path, &bar_index, config)?];
+            bar_index.finish_and_clear();
+            return Ok(());
+        }
+
+        let win_files = find_all_win_files(&self.options.input)?;
+        info!("Found {} backup file(s)", win_files.len());
+        if let Some(first_win_file) = win_files.first() {
+            check_data_partition(first_win_file, config)?;
+        }
+
+        if config.verify_checksums {
+            let bar_verify = self.add_bytes_bar(0);
+            bar_verify.set_message("Verifying TWRP backup file(s)");
+            for win_file in &win_files {
+                bar_verify.inc_length(fs::metadata(win_file)?.len());
+            }
+            win_files
+                .par_iter()
+                .try_for_each(|win_file| verify_win_file(win_file, &bar_verify, config))?;
+            bar_verify.finish_and_clear();
+        }
+
+        if !self.options.dry_run && self.options.check_free_space {
+            let mut backup_size = 0;
+            for win_file in &win_files {
+                backup_size += fs::metadata(win_file)?.len();
+            }
+            check_free_space(backup_size * DECOMPRESSION_RATIO_GUESS, config)?;
+        }
+
+        let bar_index = self.add_bytes_bar(0);
+
+        // a dry run indexes the chunks while decompressing them in memory instead of writing tars
+        self.tar_indexes = match self.options.dry_run {
+            true => {
+                bar_index.set_message("Indexing TWRP backup file(s)");
+                win_files
+                    .iter()
+                    .map(|win_file| build_win_file_index(win_file, &bar_index, config))
+                    .collect::<Result<Vec<TarIndex>, EvacuateError>>()?
+            }
+            false => {
+                // counts the compressed bytes read, the decompressed size isn't known up front
+                let bar_decompress = self.add_bytes_bar(0);
+                bar_decompress.set_message("Decompressing TWRP backup file(s)");
+
+                let tar_files = win_files
+                    .par_iter()
+                    .enumerate()
+                    .map(|(index, win_file)| {
+                        decompress_win_file(win_file, index, &bar_decompress, config)
+                    })
+                    .collect::<Result<Vec<PathBuf>, EvacuateError>>()?;
+                bar_decompress.finish_and_clear();
+
+                bar_index.set_message("Indexing TWRP backup file(s)");
+                tar_files
+                    .iter()
+                    .map(|tar_file| build_tar_index(tar_file, &bar_index, config))
+                    .collect::<Result<Vec<TarIndex>, EvacuateError>>()?
+            }
+        };
+        bar_index.finish_and_clear();
+
+        Ok(())
+    }
+
+    // finds the users and packages to migrate, indexing the backup first if needed
+    pub fn discover(&mut self) -> Result<BackupSummary, EvacuateError> {
+        if self.tar_indexes.is_empty() {
+            self.index()?;
+        }
+
+        if self.options.de_data {
+            for tar_index in &self.tar_indexes {
+                for template in detect_de_path_templates(tar_index, &self.config) {
+                    if !self.config.de_path_templates.contains(&template) {
+                        record_warning(format!(
+                            "Found device protected data in the non-standard location {}",
+                            template
+                        ));
+                        self.config.de_path_templates.push(template);
+                    }
+                }
+            }
+        }
+
+        let mut user_ids: Vec<UserId> = self.tar_indexes.iter().flat_map(find_all_users).collect();
+        user_ids.sort();
+        user_ids.dedup();
+
+        let requested_users = &self.options.users;
+        if let Some(user_id) = requested_users
+            .iter()
+            .find(|user_id| !user_ids.contains(user_id))
+        {
+            if !self.options.keep_temp {
+                self.cleanup();
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "User {} is not in the backup, found users: {}",
+                    user_id,
+                    user_ids
+                        .iter()
+                        .map(|user_id| user_id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+            .into());
+        }
+        if !requested_users.is_empty() {
+            user_ids.retain(|user_id| requested_users.contains(user_id));
+        }
+        self.user_ids = user_ids;
+
+        let config = &mut self.config;
+        config.installed_packages = self
+            .tar_indexes
+            .iter()
+            .flat_map(find_installed_packages)
+            .collect();
+
+        let mut scope = BackupScope::default();
+        for tar_index in &self.tar_indexes {
+            scan_backup_scope(
+                tar_index,
+                &self.user_ids,
+                &self.data_kinds,
+                &mut scope,
+                config,
+            );
+        }
+        let mut missing_packages: Vec<&PackageName> = config
+            .packages
+            .iter()
+            .filter(|package_name| is_selected_package(package_name, config))
+            .filter(|package_name| {
+                !scope
+                    .packages
+                    .values()
+                    .any(|package_names| package_names.contains(*package_name))
+            })
+            .collect();
+        missing_packages.sort();
+        for package_name in missing_packages {
+            record_warning(format!(
+                "Package {} was not found for any user in the backup",
+                package_name
+            ));
+        }
+
+        // restoring data without the app fails, unless it's a system app of the new device too
+        let mut packages_without_apk: Vec<&PackageName> = scope
+            .packages
+            .values()
+            .flatten()
+            .filter(|package_name| !scope.apk_packages.contains(*package_name))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        packages_without_apk.sort();
+        if !packages_without_apk.is_empty() {
+            record_warning(format!(
+                "{} package(s) have app data but no APK in the backup, their data only restores \
+                 where the app is installed already (e.g. as a system app): {}",
+                packages_without_apk.len(),
+                packages_without_apk
+                    .iter()
+                    .map(|package_name| package_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        let (user_app_count, system_app_count) = scope.packages.values().flatten().fold(
+            (0, 0),
+            |(user_apps, system_apps), package_name| match scope.apk_packages.contains(package_name)
+            {
+                true => (user_apps + 1, system_apps),
+                false => (user_apps, system_apps + 1),
+            },
+        );
+        let mut skipped_system_packages: Vec<PackageName> =
+            scope.skipped_system_packages.into_iter().collect();
+        skipped_system_packages.sort();
+
+        self.estimated_size = scope.size;
+        Ok(BackupSummary {
+            user_ids: self.user_ids.clone(),
+            user_app_count,
+            system_app_count,
+            size: scope.size,
+            skipped_system_packages,
+        })
+    }
+
+    // lists what each user's migrated backups would hold, for a dry run
+    pub fn print_plan(&self) {
+        print_migration_plan(
+            &self.tar_indexes,
+            &self.user_ids,
+            &self.config,
+            &self.data_kinds,
+        );
+    }
+
+    // lists the packages in the backup as they are, indexing it first if needed
+    pub fn print_contents(&mut self) -> Result<(), EvacuateError> {
+        if self.tar_indexes.is_empty() {
+            self.index()?;
+        }
+        print_backup_contents(&self.tar_indexes, &self.options.users, &self.config);
+        Ok(())
+    }
+
+    // extracts just the APKs into apks/<package>/, returns (package, install dir, APK count)
+    pub fn collect_apks(&self) -> Result<Vec<(PackageName, String, usize)>, EvacuateError> {
+        let bar_apk = self.add_bar(0);
+        extract_all_apks(&self.tar_indexes, &bar_apk, &self.config)?;
+        bar_apk.finish_and_clear();
+        Ok(collect_apks_only(&self.config)?)
+    }
+
+    // extracts the APKs and, for every user, each package's data into per-chunk tars
+    pub fn extract(&mut self) -> Result<(), EvacuateError> {
+        let config = &self.config;
+
+        if self.options.check_free_space {
+            if let Err(e) = check_free_space(self.estimated_size, config) {
+                if !self.options.keep_temp {
+                    self.cleanup();
+                }
+                return Err(e.into());
+            }
+        }
+
+        if self.options.resume {
+            for &user_id in &self.user_ids {
+                remove_incomplete_backups(user_id, config).map_err(|source| EvacuateError::Io {
+                    path: PathBuf::from(format!("{}/{}", config.output_dir, user_id)),
+                    source,
+                })?;
+            }
+        }
+
+        // APKs are extracted from all chunks at once, an app's split APKs may be spread over several
+        if !config.data_only {
+            let bar_apk = self.add_bar(0);
+            extract_all_apks(&self.tar_indexes, &bar_apk, config)?;
+            bar_apk.finish_and_clear();
+        }
+
+        // no data is extracted, an empty package dir has finalize() move the APKs into it. They
+        // are moved once, so an app installed for several users goes to the first one's backups
+        if config.apk_backups {
+            let mut assigned_packages = HashSet::new();
+            for &user_id in &self.user_ids {
+                for package_name in find_apk_backup_packages(&self.tar_indexes, user_id, config) {
+                    if !assigned_packages.insert(package_name.clone()) {
+                        continue;
+                    }
+                    let app_dir = format!("{}/{}/{}", config.output_dir, user_id, package_name);
+                    fs::create_dir_all(&app_dir).map_err(|source| EvacuateError::Io {
+                        path: PathBuf::from(app_dir),
+                        source,
+                    })?;
+                }
+            }
+        }
+
+        let bar_twrp_files = self.add_bar(self.tar_indexes.len() as u64);
+        for tar_index in &self.tar_indexes {
+            bar_twrp_files.set_message("Processing TWRP backup file");
+            bar_twrp_files.inc(1);
+
+            let bar_users = self.add_bar(self.user_ids.len() as u64);
+            for &user_id in &self.user_ids {
+                info!(
+                    "Processing user {} in {}",
+                    user_id,
+                    tar_index.tar_path.display()
+                );
+                bar_users.set_message("Processing user");
+                bar_users.inc(1);
+
+                for &kind in &self.data_kinds {
+                    let packages = find_all_app_data(tar_index, user_id, kind, config);
+
+                    // counts the bytes copied, the packages are counted in the message.
+                    // Packages go to separate archives, so they are extracted in parallel, each
+                    // reading the tar through its own file handle
+                    let bar_data = self.add_bytes_bar(0);
+                    let packages: Vec<PackageName> = packages
+                        .into_iter()
+                        .filter(|package_name| {
+                            let completed = self.options.resume
+                                && find_completed_backup(user_id, package_name, config).is_some();
+                            if completed {
+                                debug!(
+                                    "Skipping {} of user {}, an earlier run migrated it",
+                                    package_name, user_id
+                                );
+                            }
+                            !completed
+                        })
+                        .collect();
+                    let package_count = packages.len();
+                    let started_count = AtomicUsize::new(0);
+                    let package_timings = packages
+                        .into_par_iter()
+                        .map(|package_name| {
+                            // packages not started yet are skipped, running ones stop too
+                            check_interrupted().map_err(|source| EvacuateError::Package {
+                                user_id,
+                                package_name: package_name.clone(),
+                                source,
+                            })?;
+                            info!(
+                                "Extracting {} of {} for user {}",
+                                kind.description(),
+                                package_name,
+                                user_id
+                            );
+                            bar_data.set_message(format!(
+                                "Extracting {} ({}/{}): {}",
+                                kind.description(),
+                                started_count.fetch_add(1, Ordering::Relaxed) + 1,
+                                package_count,
+                                package_name
+                            ));
+                            let started = Instant::now();
+                            extract_app_data(
+                                tar_index,
+                                user_id,
+                                &package_name,
+                                kind,
+                                &bar_data,
+                                config,
+                            )?;
+                            Ok((package_name, started.elapsed()))
+                        })
+                        .collect::<Result<Vec<_>, EvacuateError>>()?;
+                    for (package_name, elapsed) in package_timings {
+                        *self.timings.entry(package_name).or_default() += elapsed;
+                    }
+                    bar_data.finish_and_clear();
+                }
+            }
+            bar_users.finish_and_clear();
+        }
+        bar_twrp_files.finish_and_clear();
+
+        Ok(())
+    }
+
+    // compresses the archives, writes the .properties files and lays out each backup folder,
+    // then removes the temporary files unless they are to be kept
+    pub fn finalize(&mut self) -> Result<MigrationReport, EvacuateError> {
+        let config = &self.config;
+        let backup_time = match self
+            .options
+            .backup_date
+            .and_then(|datetime| config.timezone.to_system_time(&datetime))
+        {
+            Some(backup_time) => backup_time,
+            None => get_backup_time(Path::new(&self.options.input), config.timezone)?,
+        };
+
+        let bar_assemble = self.add_bar(self.user_ids.len() as u64);
+        let mut user_reports = Vec::new();
+        for &user_id in &self.user_ids {
+            bar_assemble.set_message("Assembling Neo Backup file structure");
+            bar_assemble.inc(1);
+
+            // nothing was extracted for this user, e.g. when --package selected other users' apps
+            if !Path::new(&format!("{}/{}", config.output_dir, user_id)).is_dir() {
+                continue;
+            }
+
+            compress_migrated_tar_files(user_id, config).map_err(|source| EvacuateError::Io {
+                path: PathBuf::from(format!("{}/{}", config.output_dir, user_id)),
+                source,
+            })?;
+            let extracted_apps = find_all_extracted_apps(user_id, config)?;
+            let mut packages = Vec::new();
+            for package_name in extracted_apps
+                .into_iter()
+                .filter(|package_name| is_selected_package(package_name, config))
+            {
+                if self.options.resume {
+                    if let Some(properties_path) =
+                        find_completed_backup(user_id, &package_name, config)
+                    {
+                        packages.push(read_completed_backup(&properties_path).map_err(
+                            |source| EvacuateError::Io {
+                                path: properties_path,
+                                source,
+                            },
+                        )?);
+                        continue;
+                    }
+                }
+                check_interrupted()?;
+                info!("Assembling {} of user {}", package_name, user_id);
+                move_apks_to_destination(user_id, &package_name, config)?;
+                let properties_file = make_neo_backup_properties(
+                    user_id,
+                    &package_name,
+                    backup_time,
+                    &self.label_resolver,
+                    &self.apk_infos,
+                    config,
+                );
+                if let Some(package_report) = assemble_neo_backup_file_structure(
+                    user_id,
+                    &package_name,
+                    properties_file,
+                    config,
+                )? {
+                    packages.push(package_report);
+                }
+            }
+            user_reports.push(UserReport { user_id, packages });
+        }
+        bar_assemble.finish_and_clear();
+
+        if self.options.validate_after_assemble {
+            for &user_id in &self.user_ids {
+                if !Path::new(&format!("{}/{}", config.output_dir, user_id)).is_dir() {
+                    continue;
+                }
+                for problem in validate_assembled_backups(user_id, config)? {
+                    record_warning(format!("Validation failed: {}", problem));
+                }
+            }
+        }
+
+        let removed_temp_dirs = match self.options.keep_temp {
+            true => Vec::new(),
+            false => self.cleanup(),
+        };
+
+        Ok(MigrationReport {
+            users: user_reports,
+            warnings: warnings(),
+            removed_temp_dirs,
+        })
+    }
+
+    // removes the temporary files, returns the directories that were removed
+    pub fn cleanup(&self) -> Vec<String> {
+        cleanup_temp_dir(&self.config)
+    }
+
+    // after an interrupted run: removes the backups that weren't finished (--resume would redo
+    // them anyway) and the temporary files, unless they are kept
+    pub fn discard_unfinished(&self) {
+        for &user_id in &self.user_ids {
+            if let Err(e) = remove_incomplete_backups(user_id, &self.config) {
+                warn!(
+                    "Couldn't remove the unfinished backups of user {}: {}",
+                    user_id, e
+                );
+            }
+            // only goes when nothing was finished for the user
+            let _ = fs::remove_dir(format!("{}/{}", self.config.output_dir, user_id));
+        }
+        if !self.options.keep_temp {
+            self.cleanup();
+        }
+    }
+
+    // the users being migrated, known once discover() ran
+    pub fn user_ids(&self) -> &[UserId] {
+        &self.user_ids
+    }
+
+    // time spent extracting each package's data
+    pub fn timings(&self) -> &HashMap<PackageName, Duration> {
+        &self.timings
+    }
+
+    // packs each user's migrated backups into <user>.zip, returns the zip files written
+    pub fn zip_backups(&self) -> Result<Vec<PathBuf>, EvacuateError> {
+        let bar_zip = self.add_bytes_bar(0);
+        bar_zip.set_message("Packing the backups into zip files");
+        let mut zip_paths = Vec::new();
+        for &user_id in &self.user_ids {
+            if !Path::new(&format!("{}/{}", self.config.output_dir, user_id)).is_dir() {
+                continue;
+            }
+            let zip_path = PathBuf::from(format!("{}/{}.zip", self.config.output_dir, user_id));
+            match zip_user_backups(user_id, &bar_zip, &self.config) {
+                Ok(zip_path) => zip_paths.push(zip_path),
+                Err(source) => {
+                    // the half-written zip is of no use
+                    let _ = fs::remove_file(format!("{}.tmp", zip_path.display()));
+                    return Err(EvacuateError::Io {
+                        path: zip_path,
+                        source,
+                    });
+                }
+            }
+        }
+        bar_zip.finish_and_clear();
+        Ok(zip_paths)
+    }
+
+    // pushes the primary user's backups to a connected device with adb, returns that user; None
+    // when there is none or the push was called off
+    pub fn push_to_device(&self, device_dir: &str) -> Result<Option<UserId>, EvacuateError> {
+        let Some(&user_id) = self.user_ids.first() else {
+            return Ok(None);
+        };
+        match push_to_device(user_id, device_dir, &self.config)? {
+            true => Ok(Some(user_id)),
+            false => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::ProgressDrawTarget;
+
+    // a fresh directory for the files of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("twrp_evacuate_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_migrator(output_dir: &Path) -> Migrator {
+        let mut options = MigrationOptions::new("");
+        options.output_dir = output_dir.to_string_lossy().to_string();
+        options.dry_run = true;
+        Migrator::new(
+            options,
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        )
+        .unwrap()
+    }
+
+    // a backup tar of (path, type, content or link target) entries, each one a second newer than
+    // the one before. Paths are written as they are, so crafted ones like "/data/data/.." get in
+    fn write_test_tar(tar_path: &Path, entries: &[(&str, EntryType, &str)]) -> TarIndex {
+        let mut tar = tar::Builder::new(File::create(tar_path).unwrap());
+        for (position, (path, entry_type, data)) in entries.iter().enumerate() {
+            let mut header = Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(match entry_type {
+                EntryType::Directory => 0o771,
+                _ => 0o660,
+            });
+            header.set_mtime(1_700_000_000 + position as u64);
+            let data = match entry_type {
+                EntryType::Symlink | EntryType::Link => {
+                    header.set_link_name(data).unwrap();
+                    ""
+                }
+                _ => data,
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            tar.append(&header, data.as_bytes()).unwrap();
+        }
+        tar.into_inner().unwrap();
+        index_tar_stream(File::open(tar_path).unwrap(), tar_path).unwrap()
+    }
+
+    // (path, type, mode, link target) of each entry of a written tar
+    fn read_test_tar(tar_path: &Path) -> Vec<(PathBuf, EntryType, u32, Option<PathBuf>)> {
+        let mut archive = Archive::new(File::open(tar_path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().to_path_buf(),
+                    header.entry_type(),
+                    header.mode().unwrap(),
+                    entry
+                        .link_name()
+                        .unwrap()
+                        .map(|target| target.to_path_buf()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unsafe_package_names_are_rejected() {
+        for package_name in ["", ".", "..", "a/../b", "com.foo/bar", "com foo"] {
+            assert!(!is_safe_package_name(package_name), "{:?}", package_name);
+        }
+        assert!(is_safe_package_name("com.foo_bar.app2"));
+
+        let dir = test_dir("unsafe_names");
+        let migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/data/com.foo/files/a", EntryType::Regular, "a"),
+                ("/data/data/../escaped/files/b", EntryType::Regular, "b"),
+                ("/data/data/./files/c", EntryType::Regular, "c"),
+            ],
+        );
+        assert_eq!(
+            find_all_app_data(&index, 0, DataKind::App, &migrator.config),
+            ["com.foo"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_newest_install_dir_is_moved() {
+        let dir = test_dir("two_installs");
+        let migrator = test_migrator(&dir);
+        // a stale install from before an upgrade, with a split the current one doesn't use
+        let tar_indexes = [write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/app/com.foo-1/base.apk", EntryType::Regular, "old"),
+                (
+                    "/data/app/com.foo-1/split_config.de.apk",
+                    EntryType::Regular,
+                    "old",
+                ),
+                (
+                    "/data/app/~~abc==/com.foo-def==/base.apk",
+                    EntryType::Regular,
+                    "new",
+                ),
+            ],
+        )];
+        let apks = find_all_apks(&tar_indexes, &migrator.config);
+        assert_eq!(apks.len(), 2);
+        for apk in &apks {
+            extract_apks_to_temp(&tar_indexes, apk, &migrator.config).unwrap();
+        }
+        let apk_temp_dir = dir.join(APK_TEMP_DIR).join("com.foo");
+        assert!(apk_temp_dir.join("com.foo-1/split_config.de.apk").is_file());
+        assert!(apk_temp_dir.join("com.foo-def==/base.apk").is_file());
+
+        let app_dir = dir.join("0/com.foo");
+        fs::create_dir_all(&app_dir).unwrap();
+        move_apks_to_destination(0, &"com.foo".to_string(), &migrator.config).unwrap();
+        assert_eq!(fs::read_to_string(app_dir.join("base.apk")).unwrap(), "new");
+        assert!(!app_dir.join("split_config.de.apk").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chunks_are_ordered_whatever_the_listing_order() {
+        let expected: Vec<PathBuf> = ["win000", "win001", "win002", "win010", "win100"]
+            .iter()
+            .map(|suffix| Path::new("backup").join(format!("data.ext4.{}", suffix)))
+            .collect();
+        let mut listing = expected.clone();
+        for _ in 0..listing.len() {
+            listing.rotate_left(2);
+            for mut win_files in [listing.clone(), listing.iter().rev().cloned().collect()] {
+                sort_win_files(&mut win_files);
+                assert_eq!(win_files, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn win_file_names_are_split_into_prefix_and_chunk() {
+        assert_eq!(
+            split_win_file_name("data.ext4.win000"),
+            Some(("data.ext4", 0))
+        );
+        assert_eq!(
+            split_win_file_name("data.f2fs.win012"),
+            Some(("data.f2fs", 12))
+        );
+        for file_name in [
+            "data.ext4.win000.sha2",
+            "data.ext4.win0001",
+            "data.ext4.win01",
+            "data.ext4.winabc",
+            ".win000",
+            "data.ext4.tar",
+        ] {
+            assert_eq!(split_win_file_name(file_name), None, "{}", file_name);
+        }
+
+        // neither the checksums nor another backup sharing the start of the name are chunks
+        let dir = test_dir("win_siblings");
+        for file_name in [
+            "data.ext4.win000",
+            "data.ext4.win001",
+            "data.ext4.win000.sha2",
+            "data.extra.win000",
+        ] {
+            File::create(dir.join(file_name)).unwrap();
+        }
+        let win_files = find_all_win_files(&dir.join("data.ext4.win000").to_string_lossy());
+        assert_eq!(
+            win_files.unwrap(),
+            [dir.join("data.ext4.win000"), dir.join("data.ext4.win001")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decompressed_tar_is_named_after_the_chunk_only() {
+        let dir = test_dir("decompressed_name");
+        let migrator = test_migrator(&dir.join("out"));
+        // an uncompressed backup, a plain tar
+        let win_path = dir.join("sdcard/TWRP/BACKUPS/data.ext4.win000");
+        fs::create_dir_all(win_path.parent().unwrap()).unwrap();
+        write_test_tar(
+            &win_path,
+            &[("/data/data/com.foo", EntryType::Directory, "")],
+        );
+
+        let tar_path =
+            decompress_win_file(&win_path, 3, &ProgressBar::hidden(), &migrator.config).unwrap();
+        assert_eq!(
+            tar_path,
+            dir.join("out")
+                .join(DECOMPRESSED_TAR_DIR)
+                .join("003-data.ext4.win000.tar")
+        );
+        assert_eq!(fs::read(&tar_path).unwrap(), fs::read(&win_path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn excluded_packages_are_left_out() {
+        let dir = test_dir("excludes");
+        let mut migrator = test_migrator(&dir);
+        migrator.config.excluded_packages = vec![
+            Pattern::new("com.foo").unwrap(),
+            Pattern::new("com.google.*").unwrap(),
+        ];
+        assert!(!is_selected_package("com.foo", &migrator.config));
+        assert!(!is_selected_package(
+            "com.google.android.gms",
+            &migrator.config
+        ));
+        assert!(is_selected_package("com.foo.bar", &migrator.config));
+        assert!(is_selected_package("com.googlex", &migrator.config));
+
+        // --exclude wins over --package
+        migrator.config.packages = ["com.foo", "com.bar"].map(String::from).into();
+        assert!(!is_selected_package("com.foo", &migrator.config));
+        assert!(is_selected_package("com.bar", &migrator.config));
+        assert!(!is_selected_package("com.foo.bar", &migrator.config));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_keeps_long_link_targets() {
+        let dir = test_dir("merge");
+        let migrator = test_migrator(&dir);
+
+        let target = format!(
+            "/data/app/~~{}==/com.foo-{}==/lib/arm64",
+            "a".repeat(40),
+            "b".repeat(40)
+        );
+        assert!(target.len() > 100);
+        let chunk_path = dir.join("000-data.ext4.win000-data.tar");
+        let mut chunk = tar::Builder::new(File::create(&chunk_path).unwrap());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        chunk.append_link(&mut header, "lib", &target).unwrap();
+        chunk.into_inner().unwrap();
+
+        let archive_path = dir.join("data.tar.zst");
+        merge_tar_files(vec![chunk_path], &archive_path, &migrator.config).unwrap();
+
+        let decoder = zstd::Decoder::new(File::open(&archive_path).unwrap()).unwrap();
+        let mut archive = Archive::new(decoder);
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("lib"));
+        assert_eq!(entry.link_name().unwrap().unwrap(), Path::new(&target));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bare_chunk_name_is_looked_up_in_the_current_dir() {
+        let dir = test_dir("bare_name");
+        for file_name in ["data.ext4.win000", "data.ext4.win001"] {
+            File::create(dir.join(file_name)).unwrap();
+        }
+        // the working directory is shared by all tests, the others only use absolute paths
+        let previous_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        let win_files = find_all_win_files("data.ext4.win000");
+        std::env::set_current_dir(previous_dir).unwrap();
+        assert_eq!(
+            win_files.unwrap(),
+            [
+                Path::new("./data.ext4.win000"),
+                Path::new("./data.ext4.win001")
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn any_chunk_or_an_unsplit_backup_is_accepted() {
+        let dir = test_dir("any_chunk");
+        for file_name in [
+            "data.ext4.win000",
+            "data.ext4.win001",
+            "data.ext4.win002",
+            "data.f2fs.win",
+        ] {
+            File::create(dir.join(file_name)).unwrap();
+        }
+        let win_files =
+            |file_name: &str| find_all_win_files(&dir.join(file_name).to_string_lossy());
+
+        let chunks = win_files("data.ext4.win002").unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks, win_files("data.ext4.win000").unwrap());
+        assert_eq!(
+            win_files("data.f2fs.win").unwrap(),
+            [dir.join("data.f2fs.win")]
+        );
+        assert!(win_files("data.ext4.tar").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hard_link_pairs_stay_linked() {
+        let dir = test_dir("hard_links");
+        let mut migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/data/com.foo/files/a", EntryType::Regular, "shared"),
+                (
+                    "/data/data/com.foo/files/b",
+                    EntryType::Link,
+                    "/data/data/com.foo/files/a",
+                ),
+            ],
+        );
+        let package_name = "com.foo".to_string();
+        let bar = ProgressBar::hidden();
+        let tar_path = dir.join("0/com.foo/backup-data.tar");
+
+        extract_app_data(
+            &index,
+            0,
+            &package_name,
+            DataKind::App,
+            &bar,
+            &migrator.config,
+        )
+        .unwrap();
+        let entries = read_test_tar(&tar_path);
+        assert_eq!(entries[1].0, Path::new("files/b"));
+        assert_eq!(entries[1].1, EntryType::Link);
+        assert_eq!(entries[1].3.as_deref(), Some(Path::new("files/a")));
+
+        // with the target left out, the link gets its content
+        migrator.config.exclude_patterns = vec![Pattern::new("files/a").unwrap()];
+        extract_app_data(
+            &index,
+            0,
+            &package_name,
+            DataKind::App,
+            &bar,
+            &migrator.config,
+        )
+        .unwrap();
+        let mut archive = Archive::new(File::open(&tar_path).unwrap());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("files/b"));
+        assert_eq!(entry.header().entry_type(), EntryType::Regular);
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "shared");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chunk_with_only_a_symlink_is_kept() {
+        let dir = test_dir("symlink_only");
+        let migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/data/com.bar", EntryType::Directory, ""),
+                ("/data/data/com.foo", EntryType::Directory, ""),
+                (
+                    "/data/data/com.foo/lib",
+                    EntryType::Symlink,
+                    "/data/app/com.foo-1/lib/arm64",
+                ),
+            ],
+        );
+        let bar = ProgressBar::hidden();
+        for package_name in ["com.bar", "com.foo"] {
+            extract_app_data(
+                &index,
+                0,
+                &package_name.to_string(),
+                DataKind::App,
+                &bar,
+                &migrator.config,
+            )
+            .unwrap();
+        }
+
+        let tar_path = dir.join("0/com.foo/backup-data.tar");
+        assert!(!is_tar_empty(&tar_path, &migrator.config).unwrap());
+        // the package's own directory alone is nothing to restore
+        let tar_path = dir.join("0/com.bar/backup-data.tar");
+        assert!(is_tar_empty(&tar_path, &migrator.config).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sizes_over_8_gib_get_a_pax_record() {
+        let dir = test_dir("pax_size");
+        let migrator = test_migrator(&dir);
+        let mut tar = tar::Builder::new(Vec::new());
+        append_pax_header(&[], 1 << 20, &mut tar, &migrator.config).unwrap();
+        assert!(tar.get_ref().is_empty());
+
+        let size = 9 << 30;
+        append_pax_header(&[], size, &mut tar, &migrator.config).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_path("big.bin").unwrap();
+        header.set_size(size);
+        header.set_cksum();
+        // a placeholder, the data itself isn't needed to read the header back
+        tar.append(&header, io::empty()).unwrap();
+
+        let tar = tar.into_inner().unwrap();
+        let mut archive = Archive::new(tar.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.size(), size);
+        let size_record = entry
+            .pax_extensions()
+            .unwrap()
+            .unwrap()
+            .filter_map(Result::ok)
+            .find(|extension| extension.key() == Ok("size"))
+            .map(|extension| extension.value().unwrap().to_string());
+        assert_eq!(size_record, Some(size.to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_directories_keep_their_mode() {
+        let dir = test_dir("empty_dirs");
+        let migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/data/com.foo", EntryType::Directory, ""),
+                ("/data/data/com.foo/shared_prefs", EntryType::Directory, ""),
+            ],
+        );
+        extract_app_data(
+            &index,
+            0,
+            &"com.foo".to_string(),
+            DataKind::App,
+            &ProgressBar::hidden(),
+            &migrator.config,
+        )
+        .unwrap();
+
+        let entries = read_test_tar(&dir.join("0/com.foo/backup-data.tar"));
+        let (_, entry_type, mode, _) = entries
+            .iter()
+            .find(|(path, ..)| path == Path::new("shared_prefs"))
+            .unwrap();
+        assert_eq!(*entry_type, EntryType::Directory);
+        assert_eq!(*mode, 0o771);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newest_install_dir_is_chosen() {
+        let dir = test_dir("newest_install");
+        let migrator = test_migrator(&dir);
+        // the stale install's name sorts last, only its file time tells it apart
+        let tar_indexes = [write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                (
+                    "/data/app/com.foo-zzz==/base.apk",
+                    EntryType::Regular,
+                    "old",
+                ),
+                (
+                    "/data/app/com.foo-aaa==/base.apk",
+                    EntryType::Regular,
+                    "new",
+                ),
+            ],
+        )];
+        for apk in find_all_apks(&tar_indexes, &migrator.config) {
+            extract_apks_to_temp(&tar_indexes, &apk, &migrator.config).unwrap();
+        }
+        let apk_temp_dir = dir.join(APK_TEMP_DIR).join("com.foo");
+        assert_eq!(
+            choose_apk_instance_dir(&apk_temp_dir).unwrap(),
+            Some(apk_temp_dir.join("com.foo-aaa=="))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_entry_paths_are_normalized() {
+        for path in [
+            "/data/data/com.foo",
+            "data/data/com.foo",
+            "./data/data/com.foo",
+        ] {
+            assert_eq!(normalize_entry_path(path), "/data/data/com.foo", "{}", path);
+        }
+        assert_eq!(normalize_entry_path(".//data/app/"), "/data/app");
+        assert_eq!(normalize_entry_path("./"), "");
+
+        let dir = test_dir("relative_paths");
+        let migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("./data/app/com.foo-1/base.apk", EntryType::Regular, "apk"),
+                ("./data/data/com.foo/files/a", EntryType::Regular, "a"),
+                ("data/user/10/com.bar/files/b", EntryType::Regular, "b"),
+            ],
+        );
+        assert_eq!(find_all_users(&index), [10]);
+        assert_eq!(
+            find_installed_packages(&index),
+            ["com.foo".to_string()].into()
+        );
+        assert_eq!(
+            find_all_app_data(&index, 0, DataKind::App, &migrator.config),
+            ["com.foo"]
+        );
+        assert_eq!(
+            find_all_app_data(&index, 10, DataKind::App, &migrator.config),
+            ["com.bar"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn secondary_and_clone_users_are_migrated() {
+        let dir = test_dir("users");
+        let migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/user/95/com.foo/files/a", EntryType::Regular, "a"),
+                ("/data/user/999/com.bar/files/b", EntryType::Regular, "b"),
+            ],
+        );
+        assert_eq!(find_all_users(&index), [95, 999]);
+
+        for (user_id, package_name, file_path) in
+            [(95, "com.foo", "files/a"), (999, "com.bar", "files/b")]
+        {
+            assert_eq!(
+                find_all_app_data(&index, user_id, DataKind::App, &migrator.config),
+                [package_name]
+            );
+            extract_app_data(
+                &index,
+                user_id,
+                &package_name.to_string(),
+                DataKind::App,
+                &ProgressBar::hidden(),
+                &migrator.config,
+            )
+            .unwrap();
+            let tar_path = dir
+                .join(user_id.to_string())
+                .join(package_name)
+                .join("backup-data.tar");
+            assert_eq!(read_test_tar(&tar_path)[0].0, Path::new(file_path));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn device_protected_data_of_user_0_is_migrated() {
+        let dir = test_dir("user_0_de");
+        let mut migrator = test_migrator(&dir);
+        let index = write_test_tar(
+            &dir.join("backup.tar"),
+            &[
+                ("/data/data/com.bar/files/a", EntryType::Regular, "a"),
+                ("/data/data/com.foo/files/a", EntryType::Regular, "a"),
+                ("/data/data_de/0/com.bar/files/b", EntryType::Regular, "b"),
+                ("/data/user_de/0/com.foo/files/b", EntryType::Regular, "b"),
+            ],
+        );
+        let extract = |package_name: &str, config: &Config| {
+            extract_app_data(
+                &index,
+                0,
+                &package_name.to_string(),
+                DataKind::DeviceProtected,
+                &ProgressBar::hidden(),
+                config,
+            )
+            .unwrap();
+            let tar_path = dir
+                .join("0")
+                .join(package_name)
+                .join("backup-device_protected_files.tar");
+            read_test_tar(&tar_path)[0].0.clone()
+        };
+
+        assert_eq!(
+            find_all_app_data(&index, 0, DataKind::DeviceProtected, &migrator.config),
+            ["com.foo"]
+        );
+        assert_eq!(extract("com.foo", &migrator.config), Path::new("files/b"));
+
+        // a vendor layout outside of /data/user_de is picked up once detected
+        let templates = detect_de_path_templates(&index, &migrator.config);
+        assert_eq!(templates, ["/data/data_de/{user}"]);
+        migrator.config.de_path_templates.extend(templates);
+        assert_eq!(
+            find_all_app_data(&index, 0, DataKind::DeviceProtected, &migrator.config),
+            ["com.bar", "com.foo"]
+        );
+        assert_eq!(extract("com.bar", &migrator.config), Path::new("files/b"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn package_name_is_taken_from_the_install_dir_name() {
+        for (instance_dir_name, package_name) in [
+            (
+                "com.example.app-DqFd2HhZgfqT9Ep65qCtZQ==",
+                "com.example.app",
+            ),
+            // the base64 suffix can hold dashes itself
+            ("com.example.app--a-b_c==", "com.example.app"),
+            ("com.example.app-1", "com.example.app"),
+            ("com.example.app", "com.example.app"),
+        ] {
+            assert_eq!(instance_dir_package_name(instance_dir_name), package_name);
+        }
+    }
+}
@@ -1,736 +1,921 @@
-use chrono::{DateTime, Local};
-use flate2::read::DeflateDecoder;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use serde::Serialize;
+use chrono::NaiveDateTime;
+use clap::{
+    error::ErrorKind, parser::ValueSource, Arg, ArgMatches, CommandFactory, FromArgMatches, Parser,
+    ValueEnum,
+};
+use console::style;
+use glob::Pattern;
+use indicatif::{HumanBytes, MultiProgress, ProgressDrawTarget};
+use indicatif_log_bridge::LogWrapper;
 use std::{
-    collections::HashSet,
+    collections::HashMap,
     env,
-    fs::{self, File},
-    io::{self, Read, Write},
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal},
     path::{Path, PathBuf},
-    time::{self, SystemTime},
+    process::{self, ExitCode},
+    time::Duration,
+};
+use twrp_evacuate::{
+    available_threads, choose_data_backup, confirm_replace_output, find_data_backups,
+    find_previous_output, interrupt, is_interrupted, is_win_file_name, warnings, write_manifest,
+    write_report, write_restore_instructions, Compression, EvacuateError, MigrationOptions,
+    MigrationReport, Migrator, PackageName, ReportFormat, Timezone, UserId, APKS_DIR, APK_TEMP_DIR,
+    DECOMPRESSED_TAR_DIR, DEFAULT_CPU_ARCH, DEFAULT_DEVICE_BACKUP_DIR, DEFAULT_MAX_OPEN_ARCHIVES,
+    DEFAULT_NEO_BACKUP_VERSION, DEFAULT_OUTPUT_DIR, DEFAULT_READ_BUFFER_KIB, NEO_BACKUP_VERSIONS,
+    RESTORE_INSTRUCTIONS_FILE,
 };
-use tar::{Archive, Header};
-use walkdir::WalkDir;
-
-const DESTINATION_DIR: &str = "twrp_evacuate_migrated";
-const DECOMPRESSED_TAR_DIR: &str = "decompressed_temp";
-const APK_TEMP_DIR: &str = "apk_temp";
-
-// example of an ApkFsItem: "/data/app/~~YUW09CEoPo_qnb20Rnmw2Q==/com.machiav3lli.backup-DqFd2HhZgfqT9Ep65qCtZQ=="
-// root_dir_name: "~~YUW09CEoPo_qnb20Rnmw2Q=="
-// instance_dir_name: "com.machiav3lli.backup-DqFd2HhZgfqT9Ep65qCtZQ=="
-struct ApkFsItem {
-    root_dir_name: Option<String>,
-    instance_dir_name: String,
-}
-
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct NeoBackupProperties {
-    backup_version_code: i32,
-    package_name: String,
-    package_label: String,
-    version_name: String,
-    version_code: i32,
-    backup_date: String,
-    has_apk: bool,
-    has_app_data: bool,
-    has_devices_protected_data: bool,
-    compression_type: String,
-    cpu_arch: String,
-    size: i64,
-}
 
-struct NeoBackupPropertiesFile {
-    name: String,
-    content: NeoBackupProperties,
+/// Migrate a TWRP data backup into Neo Backup format
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path to a file of the TWRP backup, e.g. data.ext4.win000 (or data.ext4.win when it
+    /// wasn't split); the other chunks are picked up from the same folder. Can also be the
+    /// backup folder, or a folder of TWRP backups
+    #[arg(value_parser = parse_input_path, required_unless_present = "from_tar")]
+    input: Option<String>,
+
+    /// Read a backup that was already decompressed into a plain tar, instead of TWRP's files
+    #[arg(long, value_name = "PATH", conflicts_with = "input")]
+    from_tar: Option<PathBuf>,
+
+    /// When the input folder holds several data backups, migrate the newest instead of asking
+    #[arg(long)]
+    latest: bool,
+
+    /// Log what is being done; -vv also logs which files are skipped and why.
+    /// RUST_LOG takes precedence
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Config file with default options, named after them (e.g. output_dir = "...",
+    /// package = [...]). Defaults to twrp_evacuate.toml in the current directory, then in the
+    /// user's config directory
+    #[arg(long, value_name = "PATH", env = "TWRP_EVACUATE_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Directory the migrated backups are written to, created if missing
+    #[arg(long, value_name = "PATH", env = "TWRP_EVACUATE_OUTPUT", default_value = DEFAULT_OUTPUT_DIR)]
+    output_dir: String,
+
+    /// Read buffer size for backup and tar files, in KiB.
+    /// The default suits local SSDs; 1024 or more helps on USB card readers and network mounts
+    #[arg(long, value_name = "KiB", default_value_t = DEFAULT_READ_BUFFER_KIB)]
+    read_buffer: usize,
+
+    /// Maximum number of output archives being written at the same time
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_OPEN_ARCHIVES)]
+    max_open_archives: usize,
+
+    /// Number of threads used to decompress and compress, defaults to one per CPU core
+    #[arg(long, visible_alias = "jobs", value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    threads_gzip: Option<u16>,
+
+    /// Number of packages reading the decompressed backup at the same time, defaults to one per
+    /// CPU core. Lower it on spinning disks
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    threads_io: Option<u16>,
+
+    /// Only extract the installed APKs into apks/<package>/, skipping all app data
+    #[arg(long)]
+    apk_only: bool,
+
+    /// Only migrate the app data, leaving out the APKs, e.g. to reinstall the apps from a store
+    #[arg(long, conflicts_with = "apk_only")]
+    data_only: bool,
+
+    /// Only migrate the APKs, as Neo Backup backups without app data
+    #[arg(long, conflicts_with_all = ["apk_only", "data_only"])]
+    apk_backups: bool,
+
+    /// With --apk-only, also write a CSV listing the extracted packages
+    #[arg(long, value_name = "PATH", requires = "apk_only")]
+    apk_csv: Option<PathBuf>,
+
+    /// Don't check the backup files against the .sha2/.md5 digests TWRP writes next to them,
+    /// nor the checksums stored in their gzip trailers
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Only list what would be migrated for each user, without extracting or writing anything
+    #[arg(long, conflicts_with_all = ["apk_only", "push_to_device"])]
+    dry_run: bool,
+
+    /// Only print each user's packages and the APK install dir of each, without migrating
+    /// anything
+    #[arg(long, conflicts_with_all = ["dry_run", "apk_only", "resume", "push_to_device"])]
+    list: bool,
+
+    /// Keep the decompressed tars and extracted APKs instead of deleting them at the end,
+    /// to inspect them when a migration went wrong
+    #[arg(long)]
+    keep_temp: bool,
+
+    /// Go ahead even when the output directory's filesystem seems to lack the space for the
+    /// migration
+    #[arg(long)]
+    skip_space_check: bool,
+
+    /// Continue an interrupted migration into the same output directory, skipping packages
+    /// that were already migrated and redoing unfinished ones
+    #[arg(long, conflicts_with_all = ["dry_run", "apk_only"])]
+    resume: bool,
+
+    /// Delete what an earlier run left in the output directory (the migrated users, or apks/ with
+    /// --apk-only) without asking
+    #[arg(long, conflicts_with_all = ["dry_run", "list", "resume"])]
+    force: bool,
+
+    /// Skip device protected data, only migrate the regular app data
+    #[arg(long)]
+    no_de_data: bool,
+
+    /// Skip OBB expansion files (Android/obb/<package>)
+    #[arg(long)]
+    no_obb: bool,
+
+    /// Also migrate the apps' external data (Android/data/<package>), which can be huge
+    #[arg(long)]
+    external_data: bool,
+
+    /// Also migrate the apps' media files (Android/media/<package>), e.g. messengers' photos
+    /// and videos
+    #[arg(long)]
+    media_data: bool,
+
+    /// Extra location of device protected data, "{user}" is replaced by the user id
+    /// (e.g. "/data/vendor_de/{user}"). Can be given multiple times
+    #[arg(long, value_name = "TEMPLATE")]
+    de_path: Vec<String>,
+
+    /// Compression of the data archives; gzip suits older Neo Backup versions
+    #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+    compression: Compression,
+
+    /// Compression level, 0-9 for gzip and 1-22 for zstd. Lower is faster, higher makes
+    /// smaller archives; defaults to 6 for gzip and 3 for zstd
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(0..=22))]
+    compression_level: Option<u32>,
+
+    /// Drop app data entries matching this glob, relative to the package's data dir
+    /// (e.g. "**/files/Logs/**"). Can be given multiple times
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob_pattern)]
+    exclude_pattern: Vec<Pattern>,
+
+    /// Keep cache/, code_cache/ and no_backup/ and other cache files in the app data, which are
+    /// left out by default
+    #[arg(long)]
+    include_cache: bool,
+
+    /// Drop the files' SELinux labels from the backup, so they get relabeled on restore
+    #[arg(long)]
+    no_selinux: bool,
+
+    /// Replace the per-install app ids in the files' owners with placeholders (u0_a0, and
+    /// u0_a0_cache for cache files), instead of keeping the old device's
+    #[arg(long)]
+    normalize_owner: bool,
+
+    /// Only migrate this package (e.g. "com.whatsapp"). Can be given multiple times
+    #[arg(long = "package", value_name = "PACKAGE")]
+    packages: Vec<PackageName>,
+
+    /// Only migrate this user (e.g. 0 for the primary user). Can be given multiple times
+    #[arg(long = "user", value_name = "ID")]
+    users: Vec<UserId>,
+
+    /// Skip system apps, i.e. packages with data but no APK in /data/app
+    #[arg(long)]
+    skip_system: bool,
+
+    /// Skip packages matching this glob (e.g. "com.google.*"). Can be given multiple times;
+    /// wins over --package
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob_pattern)]
+    exclude: Vec<Pattern>,
+
+    /// Write minified .properties files instead of pretty-printed ones
+    #[arg(long)]
+    compact_json: bool,
+
+    /// Check each extracted APK against its v2/v3 signature digest (or at least its zip structure)
+    #[arg(long)]
+    verify_apk: bool,
+
+    /// CSV file of "package,label" lines used as app labels. Can be given multiple times,
+    /// earlier files take priority
+    #[arg(long, value_name = "CSV")]
+    labels: Vec<PathBuf>,
+
+    /// Don't color the output. The default when it isn't a terminal or NO_COLOR is set
+    #[arg(long)]
+    no_color: bool,
+
+    /// Don't show progress bars, e.g. when running from cron. The default when the output
+    /// isn't a terminal
+    #[arg(short, long, conflicts_with = "progress")]
+    quiet: bool,
+
+    /// Show progress bars even when the output isn't a terminal
+    #[arg(long)]
+    progress: bool,
+
+    /// Only print the warnings collected during the run at the end, not the full instructions
+    #[arg(long)]
+    report_warnings_only: bool,
+
+    /// Where to write the migration report (default: report.json or report.ndjson in the
+    /// output directory)
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Format of the migration report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Json)]
+    report_format: ReportFormat,
+
+    /// Also write a CSV file listing the migrated packages: user, package, label, version,
+    /// what was migrated and the size
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["dry_run", "list", "apk_only"])]
+    manifest: Option<PathBuf>,
+
+    /// Print the time spent extracting each package's data, slowest first
+    #[arg(long, conflicts_with = "apk_only")]
+    timings: bool,
+
+    /// Check every assembled backup for a consistent .properties file and backup folder
+    #[arg(long, conflicts_with = "apk_only")]
+    validate_after_assemble: bool,
+
+    /// Read every written archive back and check all of its entries are there, at the cost of
+    /// reading all migrated data once more
+    #[arg(long, conflicts_with = "apk_only")]
+    verify_output: bool,
+
+    /// Also pack each user's migrated backups into one <user>.zip, to copy a single file to the
+    /// device and unpack it there
+    #[arg(long, conflicts_with_all = ["dry_run", "list", "apk_only"])]
+    package_zip: bool,
+
+    /// After migrating, push the primary user's backups to a connected device with adb
+    #[arg(long, conflicts_with = "apk_only")]
+    push_to_device: bool,
+
+    /// CPU architecture reported for apps without native libraries
+    #[arg(long, value_name = "ABI", default_value = DEFAULT_CPU_ARCH)]
+    default_cpu_arch: String,
+
+    /// Neo Backup version the backups are made for, which sets their backupVersionCode
+    #[arg(long, value_name = "VERSION", default_value = DEFAULT_NEO_BACKUP_VERSION, value_parser = parse_neo_version)]
+    neo_version: i32,
+
+    /// Date the backup was made, e.g. "2024-11-13 10:13:38" (in --timezone). Taken from the
+    /// TWRP backup folder name by default
+    #[arg(long, value_name = "DATE", value_parser = parse_backup_date)]
+    backup_date: Option<NaiveDateTime>,
+
+    /// Timezone of the backup dates: "local", an IANA name like "Europe/Berlin" or an offset
+    /// like "+02:00". Only changes how backups are dated, not file times
+    #[arg(long, value_name = "TZ", default_value = "local")]
+    timezone: Timezone,
+
+    /// Neo Backup directory on the device, used with --push-to-device
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_DEVICE_BACKUP_DIR)]
+    device_dir: String,
 }
 
-type PackageName = String;
-
-type UserId = i32;
+const CONFIG_FILE_NAME: &str = "twrp_evacuate.toml";
 
-fn find_all_win_files(first_win_path: &str) -> Result<Vec<PathBuf>, io::Error> {
-    if !first_win_path.ends_with(".win000") {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Not a .win000 file",
-        ));
-    }
+fn find_config_file() -> Option<PathBuf> {
+    [
+        Some(PathBuf::from(CONFIG_FILE_NAME)),
+        dirs::config_dir().map(|dir| dir.join(CONFIG_FILE_NAME)),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|path| path.is_file())
+}
 
-    let first_path = Path::new(first_win_path);
-    let parent_dir = match first_path.parent() {
-        Some(parent) => parent,
-        None => {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Parent directory not found",
-            ))
+// the config file's settings as command line arguments, leaving out the options given on the
+// command line or through the environment, which take precedence
+fn config_file_args(config_path: &Path, matches: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let content = fs::read_to_string(config_path).map_err(|e| e.to_string())?;
+    let settings: toml::Table = content
+        .parse()
+        .map_err(|e: toml::de::Error| e.to_string())?;
+
+    let command = Cli::command();
+    let mut args = Vec::new();
+    for (key, value) in settings {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| {
+                // older names like jobs are still taken
+                (arg.get_long() == Some(long.as_str())
+                    || arg
+                        .get_all_aliases()
+                        .is_some_and(|aliases| aliases.contains(&long.as_str())))
+                    && arg.get_id() != "config"
+            })
+            .ok_or_else(|| format!("unknown option \"{}\"", key))?;
+        // the command line and environment win, over this option and over the ones it can't be
+        // combined with, e.g. quiet = true gives way to --progress
+        let conflicts_with = |other: &Arg| {
+            command.get_arg_conflicts_with(arg).contains(&other)
+                || command.get_arg_conflicts_with(other).contains(&arg)
+        };
+        if command
+            .get_arguments()
+            .filter(|other| other.get_id() == arg.get_id() || conflicts_with(other))
+            .any(|other| {
+                matches!(
+                    matches.value_source(other.get_id().as_str()),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            })
+        {
+            continue;
         }
-    };
-
-    let file_prefix = first_path
-        .file_stem()
-        .and_then(|stem| stem.to_str())
-        .map(|stem| stem.trim_end_matches(".win000"))
-        .unwrap_or("");
-
-    let mut win_files: Vec<PathBuf> = fs::read_dir(parent_dir)?
-        .filter_map(Result::ok)
-        .map(|entry| entry.path())
-        .filter(|path| {
-            path.file_name()
-                .and_then(|name| name.to_str())
-                .map(|file_name| file_name.starts_with(file_prefix) && file_name.contains(".win"))
-                .unwrap_or(false)
-        })
-        .collect();
 
-    win_files.sort();
-    Ok(win_files)
+        let flag = OsString::from(format!("--{}", long));
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match (value, arg.get_action().takes_values()) {
+                (toml::Value::Boolean(true), false) => args.push(flag.clone()),
+                (toml::Value::Boolean(false), false) => {}
+                // counted flags like verbose = 2
+                (toml::Value::Integer(count), false) => {
+                    args.extend((0..count).map(|_| flag.clone()));
+                }
+                (toml::Value::String(value), true) => args.extend([flag.clone(), value.into()]),
+                (toml::Value::Integer(value), true) => {
+                    args.extend([flag.clone(), value.to_string().into()]);
+                }
+                _ => return Err(format!("unexpected value for \"{}\"", key)),
+            }
+        }
+    }
+    Ok(args)
 }
 
-fn decompress_win_file(win_path: &PathBuf) -> Result<PathBuf, io::Error> {
-    let mut file = File::open(win_path)?;
+// the command line, with the config file's settings filled in for the options it doesn't give
+fn parse_cli() -> Cli {
+    let args: Vec<OsString> = env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    // skip gzip header (crc checksum) in case of corrupted files
-    let mut header = [0u8; 10];
-    file.read_exact(&mut header)?;
-
-    // decompress deflate stream directly
-    let mut reader = DeflateDecoder::new(file);
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
-
-    let tar_dir = format!("{}/{}", DESTINATION_DIR, DECOMPRESSED_TAR_DIR);
-    fs::create_dir_all(&tar_dir)?;
-
-    let tar_path = format!("{}/{}.tar", tar_dir, win_path.to_string_lossy());
-    let mut tar_file = File::create(&tar_path)?;
-    tar_file.write_all(&buffer)?;
-
-    Ok(tar_path.into())
+    let Some(config_path) = cli.config.clone().or_else(find_config_file) else {
+        return cli;
+    };
+    let file_args = config_file_args(&config_path, &matches).unwrap_or_else(|message| {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("{}: {}", config_path.display(), message),
+            )
+            .exit()
+    });
+    let (program, cli_args) = args
+        .split_first()
+        .expect("the program name is always there");
+    Cli::parse_from(
+        std::iter::once(program.clone())
+            .chain(file_args)
+            .chain(cli_args.iter().cloned()),
+    )
 }
 
-fn find_all_apks(tar_path: &PathBuf) -> Result<Vec<ApkFsItem>, io::Error> {
-    let file = File::open(tar_path)?;
-    let mut archive = Archive::new(file);
-
-    let apk_fs_items: Vec<ApkFsItem> = archive
-        .entries()?
-        .filter_map(Result::ok)
-        .filter_map(|entry| {
-            entry
-                .path()
-                .ok()
-                .and_then(|path| path.to_str().map(|s| s.to_string()))
-                .filter(|path_str| {
-                    path_str.starts_with("/data/app/") && path_str.ends_with("/base.apk")
-                })
-        })
-        .filter_map(|path_str| {
-            let mut parts = path_str.split('/');
-            let root_dir_name = parts.nth(3);
-            let instance_dir_name = parts.next();
-
-            match instance_dir_name?.ends_with(".apk") {
-                true => Some(ApkFsItem {
-                    root_dir_name: None,
-                    instance_dir_name: root_dir_name?.to_string(),
-                }),
-                false => Some(ApkFsItem {
-                    root_dir_name: root_dir_name.map(String::from),
-                    instance_dir_name: instance_dir_name?.to_string(),
-                }),
-            }
-        })
-        .collect();
-
-    Ok(apk_fs_items)
+// checked up front so a typo doesn't surface halfway through a run
+fn parse_input_path(input: &str) -> Result<String, String> {
+    if Path::new(input).is_dir() {
+        return Ok(input.to_string());
+    }
+    let file_name = Path::new(input)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    if !is_win_file_name(&file_name) {
+        return Err("expected a file of the backup, ending with .win or .winNNN".to_string());
+    }
+    match fs::metadata(input) {
+        Ok(metadata) if metadata.is_file() => Ok(input.to_string()),
+        Ok(_) => Err("not a file".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
-fn extract_apks_to_temp(tar_path: &PathBuf, apk: &ApkFsItem) -> Result<(), io::Error> {
-    let file = File::open(tar_path)?;
-    let mut archive = Archive::new(file);
-
-    let package_name = apk
-        .instance_dir_name
-        .split('-')
-        .next()
-        .unwrap_or("")
-        .to_string();
-    let apk_dir_path = match &apk.root_dir_name {
-        Some(root_dir_name) => format!("/data/app/{}/{}", root_dir_name, apk.instance_dir_name),
-        None => format!("/data/app/{}", apk.instance_dir_name),
-    };
-    let dest_dir = format!("{}/{}/{}", DESTINATION_DIR, APK_TEMP_DIR, package_name);
-
-    fs::create_dir_all(&dest_dir)?;
-
-    archive
-        .entries()?
-        .filter_map(|entry| entry.ok())
-        .filter_map(|entry| {
-            let path = entry.path().ok()?;
-            let path_str = path.to_str()?;
-
-            if path_str.starts_with(&apk_dir_path) && path_str.ends_with(".apk") {
-                let file_name = path.file_name()?.to_string_lossy().to_string();
-                Some((entry, file_name))
-            } else {
-                None
-            }
-        })
-        .try_for_each(|(mut entry, file_name)| {
-            let dest_path = format!("{}/{}", dest_dir, file_name);
-            let mut dest_file = File::create(dest_path)?;
-            io::copy(&mut entry, &mut dest_file)?;
-            Ok(())
+fn parse_neo_version(version: &str) -> Result<i32, String> {
+    NEO_BACKUP_VERSIONS
+        .iter()
+        .find(|(name, _)| *name == version)
+        .map(|(_, version_code)| *version_code)
+        .ok_or_else(|| {
+            let known: Vec<&str> = NEO_BACKUP_VERSIONS.iter().map(|(name, _)| *name).collect();
+            format!("unknown Neo Backup version, known are {}", known.join(", "))
         })
 }
 
-fn find_all_users(tar_path: &PathBuf) -> Result<Vec<i32>, io::Error> {
-    let file = File::open(tar_path)?;
-    let mut archive = Archive::new(file);
-
-    let mut user_ids: Vec<i32> = archive
-        .entries()?
-        .filter_map(|entry| entry.ok()?.path().ok()?.to_str().map(String::from))
-        .filter(|path_str| path_str.starts_with("/data/user/"))
-        .filter_map(|path_str| path_str.split('/').nth(3)?.parse::<i32>().ok())
-        .collect::<HashSet<_>>()
-        .into_iter()
-        .collect();
-
-    user_ids.sort();
-    Ok(user_ids)
+fn parse_backup_date(date: &str) -> Result<NaiveDateTime, String> {
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d--%H-%M-%S",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+    .ok_or_else(|| "expected a date like \"2024-11-13 10:13:38\"".to_string())
 }
 
-fn find_all_app_data(
-    tar_path: &PathBuf,
-    user_id: UserId,
-    is_device_protected_data: bool,
-) -> Result<Vec<PackageName>, io::Error> {
-    let file = File::open(tar_path)?;
-    let mut archive = Archive::new(file);
-
-    let base_path = match user_id {
-        0 => "/data/data/",
-        _ => &format!("/data/user/{}/", user_id),
-    };
-    let base_path = match is_device_protected_data {
-        true => &format!("/data/user_de/{}/", user_id),
-        false => base_path,
-    };
-
-    let path_depth = match user_id {
-        0 => 4,
-        _ => 5,
-    };
-    let path_depth = match is_device_protected_data {
-        true => 5,
-        false => path_depth,
-    };
-
-    let mut package_names: Vec<String> = archive
-        .entries()?
-        .filter_map(Result::ok)
-        .filter_map(|entry| {
-            entry
-                .path()
-                .ok()
-                .and_then(|path| path.to_str().map(|s| s.to_string()))
-        })
-        .filter(|path| path.starts_with(base_path))
-        .filter_map(|path| {
-            path.split('/')
-                .nth(path_depth - 1)
-                .map(|part| part.to_string())
-        })
-        .filter(|package_name| !package_name.is_empty())
-        .collect::<HashSet<_>>()
-        .into_iter()
-        .collect();
+fn parse_glob_pattern(pattern: &str) -> Result<Pattern, String> {
+    Pattern::new(pattern).map_err(|e| format!("invalid glob pattern: {}", e))
+}
 
-    package_names.sort();
-    Ok(package_names)
+fn print_warnings_report(warnings: &[String]) {
+    match warnings.len() {
+        0 => println!("{}", style("No warnings.").green()),
+        count => {
+            println!(
+                "{}",
+                style(format!("{} warning(s):", count)).yellow().bold()
+            );
+            for warning in warnings {
+                println!("  - {}", warning);
+            }
+        }
+    }
 }
 
-fn extract_app_data(
-    tar_path: &PathBuf,
-    user_id: UserId,
-    package_name: &PackageName,
-    is_de_data: bool,
-) -> Result<(), io::Error> {
-    let file = File::open(tar_path)?;
-    let mut archive = Archive::new(file);
-
-    let base_path = match user_id {
-        0 => "/data/data",
-        _ => &format!("/data/user/{}", user_id),
-    };
-    let base_path = match is_de_data {
-        true => &format!("/data/user_de/{}", user_id),
-        false => base_path,
-    };
+// counted from what the run actually migrated
+fn print_summary(report: &MigrationReport) {
+    let package_count: usize = report.users.iter().map(|user| user.packages.len()).sum();
+    let written_size: i64 = report
+        .users
+        .iter()
+        .flat_map(|user| &user.packages)
+        .map(|package| package.properties.size)
+        .sum();
 
-    let data_path = format!("{}/{}", base_path, package_name);
-    let dest_dir = format!("{}/{}/{}", DESTINATION_DIR, user_id, package_name);
-
-    let win_tar_file_name = tar_path
-        .file_stem()
-        .and_then(|name| name.to_str())
-        .unwrap_or("");
-    let dest_tar_path = match is_de_data {
-        true => format!(
-            "{}/{}-device_protected_files.tar",
-            dest_dir, win_tar_file_name
+    println!("{}", style("All done! Have fun!").green().bold());
+    println!();
+    println!(
+        "Migrated {} package(s) of {} user(s), {} written",
+        style(package_count).bold(),
+        style(report.users.len()).bold(),
+        style(HumanBytes(written_size as u64)).bold()
+    );
+    for user in &report.users {
+        println!(
+            "  User {}: {} package(s)",
+            user.user_id,
+            user.packages.len()
+        );
+    }
+    match report.warnings.len() {
+        0 => println!("{}", style("No warnings.").green()),
+        count => println!(
+            "{}",
+            style(format!("{} warning(s), listed at the end", count))
+                .yellow()
+                .bold()
         ),
-        false => format!("{}/{}-data.tar", dest_dir, win_tar_file_name),
-    };
-
-    fs::create_dir_all(&dest_dir)?;
-    let dest_tar_file = File::create(&dest_tar_path)?;
-    let mut dest_tar = tar::Builder::new(dest_tar_file);
-
-    archive
-        .entries()?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            !entry
-                .header()
-                .groupname()
-                .ok()
-                .flatten()
-                .map(|u| u.ends_with("_cache"))
-                .unwrap_or(false)
-        })
-        .filter_map(|entry| {
-            let path = entry.path().ok()?.to_path_buf();
-            if path.to_str()?.starts_with(&data_path) && path.strip_prefix(&data_path).is_ok() {
-                Some((entry, path))
-            } else {
-                None
-            }
-        })
-        .filter_map(|(entry, path)| {
-            let relative_path = path.strip_prefix(&data_path).ok()?;
-            let new_path = Path::new(".").join(relative_path);
-
-            let mut header = Header::new_gnu();
-            header.set_size(entry.header().size().ok()?);
-            header.set_entry_type(entry.header().entry_type());
-            header.set_mode(entry.header().mode().ok()?);
-            header.set_uid(entry.header().uid().ok()?);
-            header.set_gid(entry.header().gid().ok()?);
-            if let Ok(Some(username)) = entry.header().username() {
-                header.set_username(username).ok()?;
-            }
-            if let Ok(Some(groupname)) = entry.header().groupname() {
-                header.set_groupname(groupname).ok()?;
-            }
-            header.set_mtime(entry.header().mtime().ok()?);
-
-            Some((header, new_path, entry))
-        })
-        .try_for_each(|(mut header, new_path, mut entry)| {
-            dest_tar.append_data(&mut header, new_path, &mut entry)
-        })?;
+    }
+}
 
-    dest_tar.finish()?;
+fn print_timings(timings: &HashMap<PackageName, Duration>) {
+    let mut timings: Vec<_> = timings.iter().collect();
+    timings.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
 
-    Ok(())
+    println!("Time spent extracting app data:");
+    for (package_name, duration) in timings {
+        println!("  {:>10.2}s  {}", duration.as_secs_f64(), package_name);
+    }
 }
 
-fn find_all_extracted_apps(user_id: UserId) -> Result<Vec<PackageName>, io::Error> {
-    let all_app_dir = format!("{}/{}", DESTINATION_DIR, user_id);
-
-    let mut extracted_apps = Vec::new();
-    for entry in fs::read_dir(&all_app_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            let package_name = entry.file_name().to_string_lossy().to_string();
-            extracted_apps.push(package_name);
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        // the usual exit code of a program stopped by SIGINT
+        Err(e @ EvacuateError::Interrupted) => {
+            eprintln!("{}", e);
+            ExitCode::from(130)
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
         }
     }
-
-    extracted_apps.sort();
-    Ok(extracted_apps)
 }
 
-fn move_apks_to_destination(user_id: UserId, package_name: &PackageName) -> Result<(), io::Error> {
-    let app_dir = format!("{}/{}/{}", DESTINATION_DIR, user_id, package_name);
-    let apk_temp_dir = format!("{}/{}/{}", DESTINATION_DIR, APK_TEMP_DIR, package_name);
-
-    if Path::new(&app_dir).exists() && Path::new(&apk_temp_dir).exists() {
-        fs::read_dir(apk_temp_dir)?
-            .filter_map(Result::ok)
-            .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("apk"))
-            .try_for_each(|entry| {
-                let dest_path = format!("{}/{}", app_dir, entry.file_name().to_string_lossy());
-                fs::rename(entry.path(), dest_path)
-            })?;
+fn run() -> Result<(), EvacuateError> {
+    let cli = parse_cli();
+    if let Some(level) = cli.compression_level {
+        let range = cli.compression.level_range();
+        if !range.contains(&level) {
+            Cli::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "--compression-level {} is out of range for {}, use {}-{}",
+                        level,
+                        cli.compression.to_possible_value().unwrap().get_name(),
+                        range.start(),
+                        range.end()
+                    ),
+                )
+                .exit();
+        }
     }
 
-    Ok(())
-}
-
-fn get_backup_time(win_path: &PathBuf) -> Result<SystemTime, io::Error> {
-    let file = File::open(win_path)?;
-    let last_modified_time = file.metadata()?.modified()?;
-    Ok(last_modified_time)
-}
+    // colors are already left out when the output isn't a terminal
+    let no_color = cli.no_color || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if no_color {
+        console::set_colors_enabled(false);
+    }
 
-fn make_neo_backup_properties(
-    user_id: UserId,
-    package_name: &PackageName,
-    backup_time: SystemTime,
-) -> Result<NeoBackupPropertiesFile, io::Error> {
-    // https://github.com/NeoApplications/Neo-Backup/blob/main/TROUBLESHOOTING.md#faking-properties-files-if-they-are-missing-or-damaged
-    let app_dir = format!("{}/{}/{}", DESTINATION_DIR, user_id, package_name);
-
-    let has_apk = Path::new(&app_dir).join("base.apk").exists();
-    let has_app_data = Path::new(&app_dir).join("data.tar.zst").exists();
-    let has_devices_protected_data = Path::new(&app_dir)
-        .join("device_protected_files.tar.zst")
-        .exists();
-
-    let datetime: DateTime<Local> = DateTime::from(backup_time);
-    let properties_datetime = datetime.format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
-
-    let properties = NeoBackupProperties {
-        backup_version_code: 8003,
-        package_name: package_name.clone(),
-        package_label: package_name.clone(),
-        version_name: "0.0.0".to_string(),
-        version_code: 0,
-        backup_date: properties_datetime.clone(),
-        has_apk,
-        has_app_data,
-        has_devices_protected_data,
-        compression_type: "zst".to_string(),
-        cpu_arch: "arm64-v8a".to_string(), // TODO: get this from the extracted APK; at this moment i assume you don't use TWRP/NeoBackup on x86 devices/emulators!
-        size: 0,
+    // log lines go through the progress bars so they don't tear them apart
+    let m = match cli.quiet || (!cli.progress && !io::stdout().is_terminal()) {
+        true => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        false => MultiProgress::new(),
     };
-
-    let filename_datetime = datetime.format("%Y-%m-%d-%H-%M-%S-%3f").to_string();
-    let filename = format!("{}-user_{}", filename_datetime, user_id);
-
-    Ok(NeoBackupPropertiesFile {
-        name: filename,
-        content: properties,
+    let default_level = match cli.verbose {
+        0 => "error",
+        1 => "info",
+        _ => "debug",
+    };
+    let logger = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(format!("twrp_evacuate={}", default_level)),
+    )
+    .write_style(match no_color {
+        true => env_logger::WriteStyle::Never,
+        false => env_logger::WriteStyle::Auto,
     })
-}
-
-fn find_tar_files(tar_dir: &Path) -> Vec<walkdir::DirEntry> {
-    WalkDir::new(&tar_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|entry| {
-            entry.path().is_file()
-                && entry.path().extension().and_then(|ext| ext.to_str()) == Some("tar")
-        })
-        .collect()
-}
-
-fn is_tar_empty(tar_path: &Path) -> Result<bool, io::Error> {
-    let file = File::open(tar_path)?;
-    let mut archive = Archive::new(file);
-
-    Ok(archive
-        .entries()?
-        .filter_map(|entry| entry.ok())
-        .all(|entry| entry.header().entry_type() != tar::EntryType::Regular))
-}
+    .build();
+    let max_level = logger.filter();
+    LogWrapper::new(m.clone(), logger)
+        .try_init()
+        .map_err(io::Error::other)?;
+    log::set_max_level(max_level);
+
+    if let Some(threads) = cli.threads_gzip {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .build_global()
+            .map_err(io::Error::other)?;
+    }
 
-fn merge_tar_files(tar_files: Vec<PathBuf>, output_path: &Path) -> Result<(), io::Error> {
-    let output_file = File::create(output_path)?;
-    let mut output_tar = tar::Builder::new(output_file);
-
-    tar_files
-        .into_iter()
-        .try_for_each(|tar_path| -> Result<(), io::Error> {
-            let file = File::open(&tar_path)?;
-            let mut archive = Archive::new(file);
-
-            archive
-                .entries()?
-                .filter_map(|entry| entry.ok())
-                .try_for_each(|mut entry| -> Result<(), io::Error> {
-                    let mut header = entry.header().clone();
-                    let path = entry.path()?.to_path_buf();
-                    let mut data = Vec::new();
-                    entry.read_to_end(&mut data)?;
-                    output_tar.append_data(&mut header, path, &data[..])?;
-                    Ok(())
+    let input = match (&cli.from_tar, cli.input.as_deref().unwrap_or_default()) {
+        (Some(tar_path), _) => tar_path.to_string_lossy().to_string(),
+        (None, input) if Path::new(input).is_dir() => {
+            let backups =
+                find_data_backups(Path::new(input)).map_err(|source| EvacuateError::Io {
+                    path: PathBuf::from(input),
+                    source,
                 })?;
-
-            fs::remove_file(tar_path)?;
-            Ok(())
-        })?;
-
-    output_tar.finish()?;
-    Ok(())
-}
-
-fn compress_migrated_tar_files(user_id: i32) -> Result<(), io::Error> {
-    let user_dir = format!("{}/{}", DESTINATION_DIR, user_id);
-    let user_path = Path::new(&user_dir);
-
-    fs::read_dir(user_path)?
-        .filter_map(Result::ok)
-        .filter(|entry| entry.path().is_dir())
-        .par_bridge()
-        .try_for_each(|entry| {
-            let app_dir = entry.path();
-
-            let tar_files: Vec<_> = find_tar_files(&app_dir);
-
-            // delete empty tar files
-            tar_files
-                .iter()
-                .filter(|tar_file| is_tar_empty(tar_file.path()).unwrap_or(false))
-                .try_for_each(|tar_file| -> Result<(), io::Error> {
-                    fs::remove_file(tar_file.path())?;
-                    Ok(())
+            let backup =
+                choose_data_backup(backups, cli.latest).map_err(|source| EvacuateError::Io {
+                    path: PathBuf::from(input),
+                    source,
                 })?;
+            println!("Migrating {}", backup.display());
+            backup.to_string_lossy().to_string()
+        }
+        (None, input) => input.to_string(),
+    };
 
-            let tar_files: Vec<_> = find_tar_files(&app_dir);
-
-            // merge data.tar
-            let data_tar_files: Vec<_> = tar_files
-                .iter()
-                .filter(|entry| entry.path().to_str().unwrap().ends_with("-data.tar"))
-                .map(|entry| entry.path().to_path_buf())
-                .collect();
-
-            if !data_tar_files.is_empty() {
-                let output_path = app_dir.join("data.tar");
-                merge_tar_files(data_tar_files, &output_path).unwrap();
+    // new backups mixed in with stale ones of an earlier run are hard to tell apart
+    if !cli.dry_run && !cli.list && !cli.resume {
+        let previous_output = find_previous_output(&cli.output_dir, &cli.users, cli.apk_only);
+        if !previous_output.is_empty() {
+            if !cli.force && !confirm_replace_output(&previous_output)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} holds the output of an earlier run, use --force to replace it or \
+                         --resume to continue it",
+                        cli.output_dir
+                    ),
+                )
+                .into());
             }
-
-            // merge device_protected_files.tar
-            let device_protected_files_tar_files: Vec<_> = tar_files
-                .iter()
-                .filter(|entry| {
-                    entry
-                        .path()
-                        .to_str()
-                        .unwrap()
-                        .ends_with("-device_protected_files.tar")
-                })
-                .map(|entry| entry.path().to_path_buf())
-                .collect();
-
-            if !device_protected_files_tar_files.is_empty() {
-                let output_path = app_dir.join("device_protected_files.tar");
-                merge_tar_files(device_protected_files_tar_files, &output_path).unwrap();
+            for path in &previous_output {
+                fs::remove_dir_all(path).map_err(|source| EvacuateError::Io {
+                    path: path.clone(),
+                    source,
+                })?;
             }
+        }
+    }
 
-            // compress data.tar and device_protected_files.tar
-            ["data.tar", "device_protected_files.tar"]
-                .par_iter()
-                .map(|file_name| app_dir.join(file_name))
-                .filter(|tar_path| tar_path.exists())
-                .try_for_each(|tar_path| {
-                    let zst_path = tar_path.with_extension("tar.zst");
-
-                    let tar_file = File::open(&tar_path)?;
-                    let mut zst_file = File::create(&zst_path)?;
-                    let encoder = zstd::encode_all(tar_file, 0)?;
-                    zst_file.write_all(&encoder)?;
+    let options = MigrationOptions {
+        input,
+        from_tar: cli.from_tar.is_some(),
+        output_dir: cli.output_dir.clone(),
+        read_buffer_kib: cli.read_buffer,
+        max_open_archives: cli.max_open_archives,
+        tar_readers: cli.threads_io.map_or_else(available_threads, usize::from),
+        verify_checksums: !cli.no_verify,
+        // listing reads the backup like a dry run, without writing anything
+        dry_run: cli.dry_run || cli.list,
+        keep_temp: cli.keep_temp,
+        check_free_space: !cli.skip_space_check,
+        resume: cli.resume,
+        data_only: cli.data_only,
+        apk_backups: cli.apk_backups,
+        de_data: !cli.no_de_data,
+        obb: !cli.no_obb,
+        external_data: cli.external_data,
+        media_data: cli.media_data,
+        de_paths: cli.de_path.clone(),
+        exclude_patterns: cli.exclude_pattern.clone(),
+        include_cache: cli.include_cache,
+        selinux: !cli.no_selinux,
+        normalize_owner: cli.normalize_owner,
+        compression: cli.compression,
+        compression_level: cli.compression_level,
+        packages: cli.packages.clone(),
+        excluded_packages: cli.exclude.clone(),
+        users: cli.users.clone(),
+        skip_system: cli.skip_system,
+        compact_json: cli.compact_json,
+        verify_apk: cli.verify_apk,
+        labels: cli.labels.clone(),
+        apk_csv: cli.apk_csv.clone(),
+        validate_after_assemble: cli.validate_after_assemble,
+        verify_output: cli.verify_output,
+        default_cpu_arch: cli.default_cpu_arch.clone(),
+        backup_version_code: cli.neo_version,
+        backup_date: cli.backup_date,
+        timezone: cli.timezone,
+    };
+    let mut migrator = Migrator::new(options, m.clone())?;
 
-                    fs::remove_file(tar_path)?;
+    // installed only now, so Ctrl-C still simply quits the prompts above. A second Ctrl-C quits
+    // right away, without waiting for running packages
+    ctrlc::set_handler(|| {
+        if is_interrupted() {
+            process::exit(130);
+        }
+        interrupt();
+        eprintln!("Stopping, press Ctrl-C again to quit right away...");
+    })
+    .map_err(io::Error::other)?;
 
-                    Ok(())
-                })
-        })
+    let result = migrate(&cli, &mut migrator, &m);
+    // whatever failed on the way, an interrupted run leaves no half-written backups behind
+    if is_interrupted() {
+        migrator.discard_unfinished();
+        return Err(EvacuateError::Interrupted);
+    }
+    result
 }
 
-fn assemble_neo_backup_file_structure(
-    user_id: UserId,
-    package_name: &PackageName,
-    properties_file: NeoBackupPropertiesFile,
-) -> Result<(), io::Error> {
-    let app_dir = format!("{}/{}/{}", DESTINATION_DIR, user_id, package_name);
-    let filename = properties_file.name;
-    let properties = properties_file.content;
-
-    if !properties.has_apk && !properties.has_app_data && !properties.has_devices_protected_data {
+fn migrate(cli: &Cli, migrator: &mut Migrator, m: &MultiProgress) -> Result<(), EvacuateError> {
+    if cli.list {
+        migrator.print_contents()?;
+        let warnings = warnings();
+        if !warnings.is_empty() {
+            println!();
+            print_warnings_report(&warnings);
+        }
         return Ok(());
     }
 
-    let new_dir = format!("{}/{}", &app_dir, filename);
-    fs::create_dir_all(&new_dir)?;
-
-    for entry in fs::read_dir(&app_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            let dest_path = format!("{}/{}", &new_dir, entry.file_name().to_string_lossy());
-            fs::rename(&path, &dest_path)?;
+    if cli.apk_only {
+        migrator.index()?;
+        let collected = migrator.collect_apks()?;
+        let removed_temp_dirs = match cli.keep_temp {
+            true => Vec::new(),
+            false => migrator.cleanup(),
+        };
+        for temp_dir in removed_temp_dirs {
+            println!("Removed {}", temp_dir);
         }
-    }
 
-    let properties_file_path = format!("{}/{}.properties", &app_dir, filename);
-    let properties_file = File::create(properties_file_path)?;
-    serde_json::to_writer_pretty(properties_file, &properties)?;
-
-    Ok(())
-}
-
-fn cleanup_temp_dir() -> std::io::Result<()> {
-    let tar_dir = format!("{}/{}", DESTINATION_DIR, DECOMPRESSED_TAR_DIR);
-    let apk_temp_dir = format!("{}/{}", DESTINATION_DIR, APK_TEMP_DIR);
-    if Path::new(&tar_dir).exists() {
-        let _ = fs::remove_dir_all(tar_dir);
-        let _ = fs::remove_dir_all(apk_temp_dir);
+        println!();
+        println!(
+            "Extracted the APKs of {} package(s) to {}/{}.",
+            collected.len(),
+            cli.output_dir,
+            APKS_DIR
+        );
+        let warnings = warnings();
+        if !warnings.is_empty() {
+            println!();
+            print_warnings_report(&warnings);
+        }
+        return Ok(());
     }
-    Ok(())
-}
 
-fn main() -> Result<(), io::Error> {
-    let cmdline_args: Vec<String> = env::args().collect();
-    if cmdline_args.len() < 2 {
-        eprintln!("Usage: {} <path to data.ext4.win000 file>", cmdline_args[0]);
+    let summary = migrator.discover()?;
+    m.suspend(|| {
+        println!(
+            "Found {} user(s) and {} package(s) ({} user apps, {} system); estimated size {}",
+            summary.user_ids.len(),
+            summary.user_app_count + summary.system_app_count,
+            summary.user_app_count,
+            summary.system_app_count,
+            HumanBytes(summary.size)
+        );
+        if !summary.skipped_system_packages.is_empty() {
+            println!(
+                "Skipping {} system app(s) without an APK in /data/app (--skip-system): {}",
+                summary.skipped_system_packages.len(),
+                summary.skipped_system_packages.join(", ")
+            );
+        }
+    });
+
+    if cli.dry_run {
+        migrator.print_plan();
+        let warnings = warnings();
+        if !warnings.is_empty() {
+            println!();
+            print_warnings_report(&warnings);
+        }
         return Ok(());
     }
 
-    let first_win_path = &cmdline_args[1];
-    let win_files = find_all_win_files(first_win_path)?;
-
-    let m = MultiProgress::new();
-    let style = ProgressStyle::default_bar()
-        .template("{spinner:.green} {bar:20.cyan/blue} {pos}/{len} {msg}")
-        .unwrap();
-    let bar_decompress = m.add(ProgressBar::new(win_files.len() as u64));
-    bar_decompress.set_style(style.clone());
-    bar_decompress.enable_steady_tick(time::Duration::from_millis(100));
-    bar_decompress.set_message("Decompressing TWRP backup file(s)");
-
-    let tar_files = win_files
-        .par_iter()
-        .map(|win_file| {
-            let result = decompress_win_file(win_file);
-            bar_decompress.inc(1);
-            result
-        })
-        .collect::<Result<Vec<PathBuf>, io::Error>>()?;
-    bar_decompress.finish_and_clear();
-
-    let tar_file_count = tar_files.len();
-    let bar_twrp_files = m.add(ProgressBar::new(tar_file_count as u64));
-    bar_twrp_files.set_style(style.clone());
-    bar_twrp_files.enable_steady_tick(time::Duration::from_millis(100));
-    bar_twrp_files.set_message("Finding users");
-
-    let mut user_ids = tar_files
-        .iter()
-        .map(find_all_users)
-        .collect::<Result<Vec<Vec<i32>>, io::Error>>()?
-        .concat();
-    user_ids.sort();
-    user_ids.dedup();
-
-    let backup_time = get_backup_time(&PathBuf::from(first_win_path))?;
-
-    for tar_file in tar_files {
-        bar_twrp_files.set_message("Processing TWRP backup file");
-        bar_twrp_files.inc(1);
-
-        let apk_fs_items = find_all_apks(&tar_file)?;
-        let bar_apk = m.add(ProgressBar::new(apk_fs_items.len() as u64));
-        bar_apk.set_style(style.clone());
-        bar_apk.enable_steady_tick(time::Duration::from_millis(100));
-        bar_apk.set_message(format!("Found {} APK(s)", apk_fs_items.len()));
-
-        for apk_fs_item in apk_fs_items {
-            bar_apk.set_message(format!(
-                "Extracting APK: {}",
-                match apk_fs_item.root_dir_name {
-                    Some(ref root_dir_name) => root_dir_name,
-                    None => apk_fs_item.instance_dir_name.split('-').next().unwrap(),
-                }
-            ));
-            extract_apks_to_temp(&tar_file, &apk_fs_item)?;
-            bar_apk.inc(1);
-        }
-        bar_apk.finish_and_clear();
+    migrator.extract()?;
+    let report = migrator.finalize()?;
+
+    let report_path = cli.report.clone().unwrap_or_else(|| {
+        let file_name = match cli.report_format {
+            ReportFormat::Json => "report.json",
+            ReportFormat::Ndjson => "report.ndjson",
+        };
+        Path::new(&cli.output_dir).join(file_name)
+    });
+    write_report(&report, &report_path, cli.report_format).map_err(|source| EvacuateError::Io {
+        path: report_path.clone(),
+        source,
+    })?;
+
+    if let Some(manifest_path) = &cli.manifest {
+        write_manifest(&report, manifest_path).map_err(|source| EvacuateError::Io {
+            path: manifest_path.clone(),
+            source,
+        })?;
+    }
 
-        let bar_users = m.add(ProgressBar::new(user_ids.len() as u64));
-        bar_users.set_style(style.clone());
-        bar_users.enable_steady_tick(time::Duration::from_millis(100));
+    let instructions_path = write_restore_instructions(&report, &cli.output_dir, &cli.device_dir)
+        .map_err(|source| EvacuateError::Io {
+        path: Path::new(&cli.output_dir).join(RESTORE_INSTRUCTIONS_FILE),
+        source,
+    })?;
 
-        for user_id in user_ids.clone() {
-            bar_users.set_message("Processing user");
-            bar_users.inc(1);
+    let zip_paths = match cli.package_zip {
+        true => migrator.zip_backups()?,
+        false => Vec::new(),
+    };
 
-            let app_data = find_all_app_data(&tar_file, user_id, false)?;
+    if cli.report_warnings_only {
+        print_warnings_report(&report.warnings);
+        return Ok(());
+    }
 
-            let bar_data = m.add(ProgressBar::new(app_data.len() as u64));
-            bar_data.set_style(style.clone());
-            bar_data.enable_steady_tick(time::Duration::from_millis(100));
+    for temp_dir in &report.removed_temp_dirs {
+        println!("Removed {}", temp_dir);
+    }
+    if cli.keep_temp {
+        println!(
+            "Kept the temporary files in {}/{} and {}/{}",
+            cli.output_dir, DECOMPRESSED_TAR_DIR, cli.output_dir, APK_TEMP_DIR
+        );
+    }
 
-            for package_name in app_data {
-                bar_data.set_message(format!("Extracting app data: {}", package_name));
-                bar_data.inc(1);
-                extract_app_data(&tar_file, user_id, &package_name, false)?;
+    println!();
+    println!("========================================");
+    print_summary(&report);
+    println!();
+    let user_dirs: Vec<String> = migrator
+        .user_ids()
+        .iter()
+        .map(|user_id| format!("{}/{}", cli.output_dir, user_id))
+        .filter(|user_dir| Path::new(user_dir).is_dir())
+        .collect();
+    match user_dirs.split_first() {
+        Some((primary_user_dir, other_user_dirs)) => {
+            println!("Check the {} directory for the migrated backup, copy them to your device and restore them using Neo Backup.", primary_user_dir);
+            if !other_user_dirs.is_empty() {
+                println!(
+                    "Data of the other users (e.g. work profile) is in: {}",
+                    other_user_dirs.join(", ")
+                );
             }
+        }
+        None => println!("No app data was found in the backup, nothing was migrated."),
+    }
+    println!(
+        "The migration report was written to {}, and these instructions to {}",
+        report_path.display(),
+        instructions_path.display()
+    );
+    if !zip_paths.is_empty() {
+        println!(
+            "The backups were also packed into {}; Neo Backup can't read zip files, unpack them \
+             into its backup folder on the device",
+            zip_paths
+                .iter()
+                .map(|zip_path| zip_path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    println!();
+    println!("WARNING: Do not restore all backups at once! The migrated backups may contain system apps and data that are not compatible with your device. Restore only the apps you need.");
 
-            bar_data.finish_and_clear();
-
-            let app_device_protected_data = find_all_app_data(&tar_file, user_id, true)?;
+    if cli.timings {
+        println!();
+        print_timings(migrator.timings());
+    }
 
-            let bar_device_protected_data =
-                m.add(ProgressBar::new(app_device_protected_data.len() as u64));
-            bar_device_protected_data.set_style(style.clone());
-            bar_device_protected_data.enable_steady_tick(time::Duration::from_millis(100));
+    if !report.warnings.is_empty() {
+        println!();
+        print_warnings_report(&report.warnings);
+    }
 
-            for package_name in app_device_protected_data {
-                bar_device_protected_data.set_message(format!(
-                    "Extracting app device protected data: {}",
-                    package_name
-                ));
-                bar_device_protected_data.inc(1);
-                extract_app_data(&tar_file, user_id, &package_name, true)?;
-            }
-            bar_device_protected_data.finish_and_clear();
+    if cli.push_to_device {
+        println!();
+        match migrator.push_to_device(&cli.device_dir)? {
+            Some(user_id) => println!(
+                "Pushed user {}'s backups to {} on the device.",
+                user_id, cli.device_dir
+            ),
+            None => println!("Nothing was pushed to the device."),
         }
-        bar_users.finish_and_clear();
     }
-    bar_twrp_files.finish_and_clear();
-
-    let bar_assemble = m.add(ProgressBar::new(user_ids.len() as u64));
-    bar_assemble.set_style(style.clone());
-    bar_assemble.enable_steady_tick(time::Duration::from_millis(100));
-
-    for user_id in user_ids {
-        bar_assemble.set_message("Assembling Neo Backup file structure");
-        bar_assemble.inc(1);
-
-        compress_migrated_tar_files(user_id)?;
-        let extracted_apps = find_all_extracted_apps(user_id)?;
-        for package_name in extracted_apps {
-            move_apks_to_destination(user_id, &package_name)?;
-            let properties_file = make_neo_backup_properties(user_id, &package_name, backup_time)?;
-            assemble_neo_backup_file_structure(user_id, &package_name, properties_file)?;
-        }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what parse_cli parses: the config file's settings, then the command line
+    fn parse_with_config(config_path: &Path, args: &[&str]) -> Result<Cli, clap::Error> {
+        let args: Vec<OsString> = ["twrp_evacuate", "--from-tar", "backup.tar"]
+            .iter()
+            .chain(args)
+            .map(OsString::from)
+            .collect();
+        let matches = Cli::command().try_get_matches_from(&args)?;
+        let file_args = config_file_args(config_path, &matches).unwrap();
+        Cli::try_parse_from(
+            args[..1]
+                .iter()
+                .cloned()
+                .chain(file_args)
+                .chain(args[1..].iter().cloned()),
+        )
     }
-    bar_assemble.finish_and_clear();
 
-    cleanup_temp_dir()?;
+    #[test]
+    fn output_dir_flag_wins_over_the_environment() {
+        // the only test setting TWRP_EVACUATE_OUTPUT
+        env::set_var("TWRP_EVACUATE_OUTPUT", "from_env");
+        let with_flag = Cli::try_parse_from([
+            "twrp_evacuate",
+            "--from-tar",
+            "backup.tar",
+            "--output-dir",
+            "from_flag",
+        ]);
+        let without_flag = Cli::try_parse_from(["twrp_evacuate", "--from-tar", "backup.tar"]);
+        env::remove_var("TWRP_EVACUATE_OUTPUT");
+        let default = Cli::try_parse_from(["twrp_evacuate", "--from-tar", "backup.tar"]);
+
+        assert_eq!(with_flag.unwrap().output_dir, "from_flag");
+        assert_eq!(without_flag.unwrap().output_dir, "from_env");
+        assert_eq!(default.unwrap().output_dir, DEFAULT_OUTPUT_DIR);
+    }
 
-    println!();
-    println!("========================================");
-    println!("All done! Have fun!");
-    println!();
-    println!("Check the {}/0 directory for the migrated backup, copy them to your device and restore them using Neo Backup.", DESTINATION_DIR);
-    println!("If you have more than one user (e.g. work profile), you can find the other users' data in the respective directories (e.g. {}/10, {}/11, etc.)", DESTINATION_DIR, DESTINATION_DIR);
-    println!();
-    println!("WARNING: Do not restore all backups at once! The migrated backups may contain system apps and data that are not compatible with your device. Restore only the apps you need.");
+    #[test]
+    fn command_line_wins_over_the_config_file() {
+        let config_path =
+            env::temp_dir().join(format!("twrp_evacuate_config_{}.toml", process::id()));
+        fs::write(
+            &config_path,
+            "quiet = true\napk_only = true\nmax_open_archives = 4\n",
+        )
+        .unwrap();
 
-    Ok(())
+        let cli = parse_with_config(&config_path, &[]).unwrap();
+        assert!(cli.quiet && cli.apk_only);
+        assert_eq!(cli.max_open_archives, 4);
+        let cli = parse_with_config(&config_path, &["--max-open-archives", "8"]).unwrap();
+        assert_eq!(cli.max_open_archives, 8);
+        // options conflicting with the file's settings replace them instead of failing
+        let cli = parse_with_config(&config_path, &["--progress"]).unwrap();
+        assert!(cli.progress && !cli.quiet);
+        let cli = parse_with_config(&config_path, &["--data-only"]).unwrap();
+        assert!(cli.data_only && !cli.apk_only);
+        let cli = parse_with_config(&config_path, &["--dry-run"]).unwrap();
+        assert!(cli.dry_run && !cli.apk_only);
+        fs::remove_file(&config_path).unwrap();
+    }
 }